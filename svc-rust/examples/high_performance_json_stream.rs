@@ -2,7 +2,6 @@ use std::fs::File;
 use std::io::{BufReader, BufWriter, Write};
 use std::path::{Path, PathBuf};
 use serde::{Deserialize, Serialize};
-use serde_json;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Event {
@@ -28,7 +27,7 @@ pub(crate) fn process_json_file(
     let records: Vec<Event> = serde_json::from_reader(reader)?;
     let actors: Vec<Actor> = records
         .into_iter()
-        .filter_map(|record| Some(record.actor))
+        .map(|record| record.actor)
         .collect();
 
     // Prepare output file path
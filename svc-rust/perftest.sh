@@ -12,7 +12,7 @@ TEST_ARCHIVE="$PROJECT_DIR/ArchiveLarge.zip"
 # Kill any existing processes using port 8080
 lsof -ti:8080 | xargs -r kill -9 || true
 
-rm -f performance_results.md performance_results.json system_resources.txt
+rm -f performance_results.md performance_results.json system_resources.txt metrics.txt
 rm -rf "$PROJECT_DIR/tmp"
 rm -rf "$PROJECT_DIR/tmp-large"
 rm -rf "$PROJECT_DIR/uploads"
@@ -54,6 +54,10 @@ hyperfine \
 echo "Capturing system resources..."
 top -l 1 -n 5 > system_resources.txt
 
+# Capture pipeline metrics
+echo "Capturing service metrics..."
+curl -s http://localhost:8080/metrics > metrics.txt
+
 # Kill the service
 kill $SERVICE_PID || true
 
@@ -63,6 +67,7 @@ echo "Results saved in:"
 echo "- performance_results.md"
 echo "- performance_results.json"
 echo "- system_resources.txt"
+echo "- metrics.txt"
 
 # Optional: Show markdown results
 cat performance_results.md
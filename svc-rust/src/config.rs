@@ -7,9 +7,32 @@ pub struct AppConfig {
     pub upload_dir: String,
     pub large_upload_dir: String,
     pub max_file_size_mb: usize,
+    /// Limit on the combined uncompressed size of all archive entries
+    pub max_uncompressed_size_mb: usize,
+    /// Limit on the uncompressed size of a single archive entry
+    pub max_entry_size_mb: usize,
+    /// Limit on the number of entries in an archive
+    pub max_archive_entries: usize,
+    /// Limit on how many times an entry may expand when decompressed
+    pub max_compression_ratio: u64,
     pub upload_file_name: String,
     pub server_host: String,
     pub server_port: u16,
+    /// Threads dedicated to ZIP extraction and JSON parsing
+    pub blocking_pool_size: usize,
+    /// Tasks that may wait for a blocking thread before uploads are rejected
+    pub blocking_queue_size: usize,
+    /// Free space each data directory needs for the service to report ready
+    pub min_free_disk_mb: u64,
+    /// Distinct actors the `unique_actors` and `actor_activity` extractors
+    /// keep in memory before spilling to disk
+    pub max_actors_in_memory: usize,
+    /// Extracted files one job parses at the same time
+    pub max_parallel_files: usize,
+    /// How long a finished job and its files are kept, in seconds
+    pub job_ttl_secs: u64,
+    /// Jobs kept at most; the oldest finished ones are removed first
+    pub max_jobs: usize,
 }
 
 impl AppConfig {
@@ -22,23 +45,71 @@ impl AppConfig {
             max_file_size_mb: env::var("MAX_FILE_SIZE_MB")
                 .map(|v| v.parse().unwrap_or(500))
                 .unwrap_or(500),
+            max_uncompressed_size_mb: env::var("MAX_UNCOMPRESSED_SIZE_MB")
+                .map(|v| v.parse().unwrap_or(5000))
+                .unwrap_or(5000),
+            max_entry_size_mb: env::var("MAX_ENTRY_SIZE_MB")
+                .map(|v| v.parse().unwrap_or(2000))
+                .unwrap_or(2000),
+            max_archive_entries: env::var("MAX_ARCHIVE_ENTRIES")
+                .map(|v| v.parse().unwrap_or(10000))
+                .unwrap_or(10000),
+            max_compression_ratio: env::var("MAX_COMPRESSION_RATIO")
+                .map(|v| v.parse().unwrap_or(100))
+                .unwrap_or(100),
             upload_file_name: env::var("UPLOAD_FILE_NAME").unwrap_or_else(|_| "upload.zip".to_string()),
             server_host: env::var("SERVER_HOST").unwrap_or_else(|_| "127.0.0.1".to_string()),
             server_port: env::var("SERVER_PORT")
                 .map(|v| v.parse().unwrap_or(8080))
                 .unwrap_or(8080),
+            blocking_pool_size: env::var("BLOCKING_POOL_SIZE")
+                .map(|v| v.parse().unwrap_or_else(|_| default_pool_size()))
+                .unwrap_or_else(|_| default_pool_size()),
+            blocking_queue_size: env::var("BLOCKING_QUEUE_SIZE")
+                .map(|v| v.parse().unwrap_or(64))
+                .unwrap_or(64),
+            min_free_disk_mb: env::var("MIN_FREE_DISK_MB")
+                .map(|v| v.parse().unwrap_or(1024))
+                .unwrap_or(1024),
+            max_actors_in_memory: env::var("MAX_ACTORS_IN_MEMORY")
+                .map(|v| v.parse().unwrap_or(100_000))
+                .unwrap_or(100_000),
+            max_parallel_files: env::var("MAX_PARALLEL_FILES")
+                .map(|v| v.parse().unwrap_or_else(|_| default_pool_size()))
+                .unwrap_or_else(|_| default_pool_size()),
+            job_ttl_secs: env::var("JOB_TTL_SECS")
+                .map(|v| v.parse().unwrap_or(3600))
+                .unwrap_or(3600),
+            max_jobs: env::var("MAX_JOBS")
+                .map(|v| v.parse().unwrap_or(1000))
+                .unwrap_or(1000),
         }
     }
 
+    /// Directories uploads and results are written to
+    pub fn data_dirs(&self) -> [&str; 4] {
+        [
+            &self.json_dir,
+            &self.upload_dir,
+            &self.large_json_dir,
+            &self.large_upload_dir,
+        ]
+    }
+
     pub fn create_dirs(&self) -> Result<(), std::io::Error> {
-        std::fs::create_dir_all(&self.json_dir)?;
-        std::fs::create_dir_all(&self.upload_dir)?;
-        std::fs::create_dir_all(&self.large_json_dir)?;
-        std::fs::create_dir_all(&self.large_upload_dir)?;
+        for dir in self.data_dirs() {
+            std::fs::create_dir_all(dir)?;
+        }
         Ok(())
     }
 }
 
+fn default_pool_size() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4)
+}
+
 impl Default for AppConfig {
     fn default() -> Self {
         Self::new()
@@ -49,8 +120,8 @@ impl std::fmt::Display for AppConfig {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "JSON_DIR: {}, LARGE_JSON_DIR: {}, UPLOAD_DIR: {}, LARGE_UPLOAD_DIR: {}, MAX_FILE_SIZE_MB: {}, UPLOAD_FILE_NAME: {}, SERVER_HOST: {}, SERVER_PORT: {}",
-            self.json_dir, self.large_json_dir, self.upload_dir, self.large_upload_dir, self.max_file_size_mb, self.upload_file_name, self.server_host, self.server_port
+            "JSON_DIR: {}, LARGE_JSON_DIR: {}, UPLOAD_DIR: {}, LARGE_UPLOAD_DIR: {}, MAX_FILE_SIZE_MB: {}, MAX_UNCOMPRESSED_SIZE_MB: {}, MAX_ENTRY_SIZE_MB: {}, MAX_ARCHIVE_ENTRIES: {}, MAX_COMPRESSION_RATIO: {}, UPLOAD_FILE_NAME: {}, SERVER_HOST: {}, SERVER_PORT: {}, BLOCKING_POOL_SIZE: {}, BLOCKING_QUEUE_SIZE: {}, MIN_FREE_DISK_MB: {}, MAX_ACTORS_IN_MEMORY: {}, MAX_PARALLEL_FILES: {}, JOB_TTL_SECS: {}, MAX_JOBS: {}",
+            self.json_dir, self.large_json_dir, self.upload_dir, self.large_upload_dir, self.max_file_size_mb, self.max_uncompressed_size_mb, self.max_entry_size_mb, self.max_archive_entries, self.max_compression_ratio, self.upload_file_name, self.server_host, self.server_port, self.blocking_pool_size, self.blocking_queue_size, self.min_free_disk_mb, self.max_actors_in_memory, self.max_parallel_files, self.job_ttl_secs, self.max_jobs
         )
     }
 }
@@ -0,0 +1,196 @@
+use std::fmt;
+
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::Serialize;
+use zip::result::ZipError;
+
+use crate::utils::blocking_pool::PoolError;
+use crate::utils::limits::LimitError;
+
+/// Errors raised while receiving, extracting and processing uploads.
+///
+/// Each variant has a stable `code` and HTTP status; the codes match the ones
+/// the Go service returns so clients can handle both the same way.
+#[derive(Debug)]
+pub enum AppError {
+    /// The request itself is malformed (bad query string, broken multipart)
+    InvalidRequest(String),
+    /// The body could not be received
+    Upload(String),
+    /// The upload or the archive inside it went over a size limit
+    Limit(LimitError),
+    /// The upload is not an archive we can read
+    UnsupportedType(String),
+    /// The archive is readable but its contents are not (empty, corrupted)
+    Extraction(String),
+    /// An extracted file is not valid event JSON
+    Processing(String),
+    /// The processing queue is full
+    Busy,
+    NotFound(String),
+    /// The job exists but is not in a state that allows the request
+    JobNotReady(String),
+    /// Anything on our side: disk I/O, a crashed task
+    Internal(String),
+}
+
+/// JSON body returned for every error
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+}
+
+impl AppError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidRequest(_) => "INVALID_REQUEST",
+            Self::Upload(_) => "FILE_UPLOAD_ERROR",
+            Self::Limit(_) => "FILE_SIZE_ERROR",
+            Self::UnsupportedType(_) => "FILE_TYPE_ERROR",
+            Self::Extraction(_) => "FILE_EXTRACTION_ERROR",
+            Self::Processing(_) => "FILE_PROCESSING_ERROR",
+            Self::Busy => "SERVICE_BUSY",
+            Self::NotFound(_) => "NOT_FOUND",
+            Self::JobNotReady(_) => "JOB_NOT_READY",
+            Self::Internal(_) => "INTERNAL_ERROR",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::InvalidRequest(_) => "Invalid request",
+            Self::Upload(_) => "Failed to upload file",
+            Self::Limit(_) => "File size exceeds limit",
+            Self::UnsupportedType(_) => "Invalid file type",
+            Self::Extraction(_) => "Failed to extract files",
+            Self::Processing(_) => "Failed to process files",
+            Self::Busy => "Service is busy, try again later",
+            Self::NotFound(_) => "Resource not found",
+            Self::JobNotReady(_) => "Job is not finished",
+            Self::Internal(_) => "Internal server error",
+        }
+    }
+
+    pub fn details(&self) -> Option<String> {
+        match self {
+            Self::InvalidRequest(details)
+            | Self::Upload(details)
+            | Self::UnsupportedType(details)
+            | Self::Extraction(details)
+            | Self::Processing(details)
+            | Self::NotFound(details)
+            | Self::JobNotReady(details)
+            | Self::Internal(details) => Some(details.clone()),
+            Self::Limit(limit) => Some(limit.to_string()),
+            Self::Busy => None,
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        ErrorBody {
+            code: self.code(),
+            message: self.message(),
+            details: self.details(),
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.details() {
+            Some(details) => write!(f, "{}: {}", self.message(), details),
+            None => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::InvalidRequest(_) | Self::Upload(_) => StatusCode::BAD_REQUEST,
+            Self::Limit(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::UnsupportedType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::Extraction(_) | Self::Processing(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Busy => StatusCode::SERVICE_UNAVAILABLE,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::JobNotReady(_) => StatusCode::CONFLICT,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if self.status_code().is_server_error() {
+            tracing::error!("{}", self);
+        }
+        HttpResponse::build(self.status_code()).json(self.body())
+    }
+}
+
+impl From<LimitError> for AppError {
+    fn from(error: LimitError) -> Self {
+        Self::Limit(error)
+    }
+}
+
+impl From<PoolError> for AppError {
+    fn from(error: PoolError) -> Self {
+        match error {
+            PoolError::Saturated => Self::Busy,
+            PoolError::Canceled => Self::Internal(error.to_string()),
+        }
+    }
+}
+
+impl AppError {
+    /// Classifies an I/O error raised while reading or writing upload data
+    fn from_io(error: &std::io::Error) -> Self {
+        // Limits enforced inside readers surface as I/O errors
+        if let Some(limit) = LimitError::from_io(error) {
+            return Self::Limit(limit);
+        }
+        match error.kind() {
+            // Raised while decompressing a damaged archive entry
+            std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof => {
+                Self::Extraction(error.to_string())
+            }
+            _ => Self::Internal(error.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(error: std::io::Error) -> Self {
+        Self::from_io(&error)
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(error: serde_json::Error) -> Self {
+        if error.is_io() {
+            // Unwraps the original I/O error, so limits raised by the reader
+            // are still recognized
+            return Self::from(std::io::Error::from(error));
+        }
+        Self::Processing(error.to_string())
+    }
+}
+
+impl From<ZipError> for AppError {
+    fn from(error: ZipError) -> Self {
+        match error {
+            ZipError::Io(error) => error.into(),
+            other => Self::Extraction(other.to_string()),
+        }
+    }
+}
+
+impl From<actix_multipart::MultipartError> for AppError {
+    fn from(error: actix_multipart::MultipartError) -> Self {
+        Self::Upload(error.to_string())
+    }
+}
@@ -0,0 +1,91 @@
+use crate::config::AppConfig;
+use crate::utils::blocking_pool::BlockingPool;
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use serde_json::json;
+use std::path::Path;
+use uuid::Uuid;
+
+const MB: u64 = 1024 * 1024;
+
+/// Outcome of one readiness check
+#[derive(Debug, Serialize)]
+struct Check {
+    name: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, result: Result<(), String>) -> Self {
+        let (ok, detail) = match result {
+            Ok(()) => (true, None),
+            Err(detail) => (false, Some(detail)),
+        };
+        Self {
+            name: name.into(),
+            ok,
+            detail,
+        }
+    }
+}
+
+/// Creates and removes a probe file to prove `dir` accepts writes
+fn check_writable(dir: &str) -> Result<(), String> {
+    let probe = Path::new(dir).join(format!(".ready-{}", Uuid::new_v4()));
+    std::fs::write(&probe, b"")
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|e| format!("{} is not writable: {}", dir, e))
+}
+
+fn check_free_space(dir: &str, min_free_mb: u64) -> Result<(), String> {
+    let available = fs4::available_space(dir)
+        .map_err(|e| format!("Could not read free space of {}: {}", dir, e))?;
+    if available < min_free_mb * MB {
+        return Err(format!(
+            "{} has {} MB free, below the minimum of {} MB",
+            dir,
+            available / MB,
+            min_free_mb
+        ));
+    }
+    Ok(())
+}
+
+fn check_queue(pool: &BlockingPool) -> Result<(), String> {
+    if pool.is_saturated() {
+        return Err(format!("Processing queue is full ({} tasks waiting)", pool.queued()));
+    }
+    Ok(())
+}
+
+/// The process is up and serving requests
+#[get("/health/live")]
+pub async fn liveness() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+/// The service can take new uploads: its data directories are writable and
+/// have enough free space, and the processing queue has room
+#[get("/health/ready")]
+pub async fn readiness(
+    config: web::Data<AppConfig>,
+    pool: web::Data<BlockingPool>,
+) -> HttpResponse {
+    let mut checks = Vec::new();
+    for dir in config.data_dirs() {
+        checks.push(Check::new(format!("writable:{}", dir), check_writable(dir)));
+        checks.push(Check::new(
+            format!("disk_space:{}", dir),
+            check_free_space(dir, config.min_free_disk_mb),
+        ));
+    }
+    checks.push(Check::new("processing_queue", check_queue(&pool)));
+
+    if checks.iter().all(|check| check.ok) {
+        HttpResponse::Ok().json(json!({ "status": "ready", "checks": checks }))
+    } else {
+        HttpResponse::ServiceUnavailable().json(json!({ "status": "not_ready", "checks": checks }))
+    }
+}
@@ -0,0 +1,33 @@
+use crate::error::AppError;
+use crate::handlers::results::open_artifact;
+use crate::jobs::{JobState, JobStore};
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use uuid::Uuid;
+
+#[get("/jobs/{job_id}")]
+pub async fn job_status(
+    jobs: web::Data<JobStore>,
+    job_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    match jobs.get(&job_id) {
+        Some(status) => Ok(HttpResponse::Ok().json(status)),
+        None => Err(AppError::NotFound(format!("Job {} not found", job_id))),
+    }
+}
+
+#[get("/jobs/{job_id}/result")]
+pub async fn job_result(
+    req: HttpRequest,
+    jobs: web::Data<JobStore>,
+    job_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let Some(status) = jobs.get(&job_id) else {
+        return Err(AppError::NotFound(format!("Job {} not found", job_id)));
+    };
+
+    if status.state != JobState::Done {
+        return Err(AppError::JobNotReady(format!("Job {} has no result yet", job_id)));
+    }
+
+    Ok(open_artifact(&status.result_path).await?.into_response(&req))
+}
@@ -0,0 +1,13 @@
+use crate::error::AppError;
+use crate::metrics::METRICS;
+use actix_web::{get, HttpResponse};
+use prometheus::TEXT_FORMAT;
+
+/// Exposes the service metrics for Prometheus to scrape
+#[get("/metrics")]
+pub async fn export_metrics() -> Result<HttpResponse, AppError> {
+    let body = METRICS
+        .render()
+        .map_err(|e| AppError::Internal(format!("Failed to encode metrics: {}", e)))?;
+    Ok(HttpResponse::Ok().content_type(TEXT_FORMAT).body(body))
+}
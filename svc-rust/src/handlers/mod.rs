@@ -1,4 +1,12 @@
+mod health;
+mod jobs;
+mod metrics;
+pub(crate) mod processing;
+mod results;
 mod upload;
-mod processing;
 
-pub use upload::{upload_zip, upload_large_zip};
+pub use health::{liveness, readiness};
+pub use jobs::{job_result, job_status};
+pub use metrics::export_metrics;
+pub use results::download_result;
+pub use upload::{submit_upload_job, submit_upload_large_job, upload_large_zip, upload_zip};
\ No newline at end of file
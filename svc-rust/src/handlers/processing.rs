@@ -1,127 +1,701 @@
-use actix_web::web;
-
 use crate::config::AppConfig;
-use crate::types::Actor;
-use crate::utils::json_processing::{
-    process_json_file, process_large_json_stream,
-};
+use crate::error::AppError;
+use crate::metrics::METRICS;
+use crate::types::Event;
+use crate::utils::extraction::{self, ExtractionReport};
+use crate::utils::event_query::{EventFilter, Projection};
+use crate::utils::extractors::{ExtractorKind, ExtractorOptions, ExtractorOutput, ExtractorSet};
+use crate::utils::file_processing::{self, ArchiveFormat};
+use crate::utils::compression::{self, Compression, COMPRESSED_UPLOAD_ENTRY};
+use crate::utils::limits::{ArchiveBudget, ArchiveLimits};
+use crate::utils::output::{OutputFormat, OutputSink, ResponseSender};
+use crate::utils::json_processing::{process_json_stream, InvalidRecord, Rewound};
+use crate::utils::validation::{ErrorLog, ValidationPolicy, ValidationReport};
+use crate::utils::zip_stream::{self, ChunkReader, ChunkReceiver, StreamOutcome};
+use crate::workspace::Workspace;
+use std::cell::Cell;
+use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::fs::File;
-use std::io::{BufWriter, Write};
-use std::path::{Path, PathBuf};
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+/// Strategy that parses a single file, hands every event to the first
+/// callback and every invalid record to the second, and returns the number of
+/// events parsed
+type ProcessingStrategy = Box<
+    dyn Fn(
+            &mut dyn Read,
+            &mut dyn FnMut(Event) -> Result<(), AppError>,
+            &mut dyn FnMut(InvalidRecord) -> Result<(), AppError>,
+        ) -> Result<usize, AppError>
+        + Send
+        + Sync,
+>;
+
+/// Events a file worker may parse ahead of the extractors
+const FILE_EVENT_BUFFER: usize = 1024;
+
+/// What a file worker hands the extractors, in file order
+enum Parsed {
+    /// Boxed, as events are far larger than invalid records
+    Event(Box<Event>),
+    Invalid(InvalidRecord),
+}
+
+/// Output name reported for records streamed back in the response
+const INLINE_OUTPUT: &str = "response";
+
+/// Everything that shapes how one job is processed, beyond its mode
+#[derive(Debug, Clone)]
+pub(crate) struct ProcessingOptions {
+    pub extractors: ExtractorOptions,
+    /// Most extracted files parsed at the same time
+    pub max_parallel_files: usize,
+    /// Format the output files are written in
+    pub format: OutputFormat,
+    /// Conditions events have to meet to be extracted
+    pub filter: EventFilter,
+    /// Fields kept in the output records, all of them when `None`
+    pub fields: Option<Projection>,
+    /// What happens to invalid records, the mode's default when `None`
+    pub validation: Option<ValidationPolicy>,
+    /// Streams the records of the single selected extractor into a response
+    /// body instead of an output file
+    pub inline: Option<ResponseSender>,
+}
+
+impl ProcessingOptions {
+    pub(crate) fn from_config(
+        config: &AppConfig,
+        kinds: Vec<ExtractorKind>,
+        format: OutputFormat,
+    ) -> Self {
+        Self {
+            extractors: ExtractorOptions::from_config(config, kinds),
+            max_parallel_files: config.max_parallel_files,
+            format,
+            filter: EventFilter::default(),
+            fields: None,
+            validation: None,
+            inline: None,
+        }
+    }
+}
+
+/// Which of the processing pipelines an upload runs through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ProcessingMode {
+    /// Fail on the first invalid record (`process_json_dir`)
+    Standard,
+    /// Skip invalid records, for large dumps where a few broken records are
+    /// expected (`process_large_json_dir`)
+    Large,
+}
+
+impl ProcessingMode {
+    /// Returns the `(upload_dir, json_dir)` pair this mode works in
+    pub(crate) fn dirs<'a>(&self, config: &'a AppConfig) -> (&'a str, &'a str) {
+        match self {
+            Self::Standard => (&config.upload_dir, &config.json_dir),
+            Self::Large => (&config.large_upload_dir, &config.large_json_dir),
+        }
+    }
+
+    /// Name of the file an extractor writes to inside the workspace output
+    /// dir, e.g. `actors.json` or `actors-stream.csv`
+    pub(crate) fn output_filename(&self, extractor: &str, format: OutputFormat) -> String {
+        match self {
+            Self::Standard => format!("{}.{}", extractor, format.extension()),
+            Self::Large => format!("{}-stream.{}", extractor, format.extension()),
+        }
+    }
+
+    pub(crate) fn run(
+        &self,
+        workspace: &Workspace,
+        options: &ProcessingOptions,
+    ) -> Result<ProcessingSummary, AppError> {
+        match self {
+            Self::Standard => process_json_dir(workspace, options),
+            Self::Large => process_large_json_dir(workspace, options),
+        }
+    }
+
+    /// Value of the `mode` label on processing metrics
+    pub(crate) fn label(&self) -> &'static str {
+        match self {
+            Self::Standard => "standard",
+            Self::Large => "large",
+        }
+    }
+
+    /// What happens to invalid records when the request does not say
+    pub(crate) fn default_validation(&self) -> ValidationPolicy {
+        match self {
+            Self::Standard => ValidationPolicy::FailFast,
+            Self::Large => ValidationPolicy::Skip,
+        }
+    }
+
+    fn processing_config(
+        &self,
+        filter: &EventFilter,
+        validation: Option<ValidationPolicy>,
+    ) -> ProcessingConfig {
+        let label = self.label();
+        let parse_duration = METRICS.parse_duration.with_label_values(&[label]);
+        let processing_strategy: ProcessingStrategy = Box::new(move |reader, on_event, on_invalid| {
+            parse_duration
+                .observe_closure_duration(|| process_json_stream(reader, label, on_event, on_invalid))
+        });
+        ProcessingConfig {
+            mode: *self,
+            processing_strategy,
+            filter: filter.clone(),
+            validation: validation.unwrap_or(self.default_validation()),
+        }
+    }
+}
+
+/// Counts reported back to the client once a workspace has been processed
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ProcessingSummary {
+    pub files_processed: usize,
+    pub actors: usize,
+    /// One entry per extractor that ran
+    pub outputs: Vec<ExtractorOutput>,
+    /// Records left out because they could not be read as events
+    pub validation: ValidationReport,
+}
 
-// #[derive(Debug)]
 struct ProcessingConfig {
-    /// Filename to exclude from processing
-    exclude_filename: &'static str,
-    /// Output filename
-    output_filename: &'static str,
+    /// Mode the output files are named after
+    mode: ProcessingMode,
     /// Processing strategy (closure that defines how to process files)
-    processing_strategy: Box<dyn Fn(&AppConfig, &Path) -> Result<Vec<Actor>, Box<dyn std::error::Error>>>,
-    /// Large processing strategy (for stream processing)
-    large_processing_strategy: Box<dyn Fn(&AppConfig, &Path) -> Result<(), Box<dyn std::error::Error>>>,
+    processing_strategy: ProcessingStrategy,
+    /// Events that fail it are dropped as they are parsed
+    filter: EventFilter,
+    /// What happens to records that are not events
+    validation: ValidationPolicy,
 }
 
 impl Debug for ProcessingConfig {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ProcessingConfig")
-            .field("exclude_filename", &self.exclude_filename)
-            .field("output_filename", &self.output_filename)
+            .field("mode", &self.mode)
+            .field("filter", &self.filter)
+            .field("validation", &self.validation)
             .finish()
     }
 }
 
 impl Default for ProcessingConfig {
     fn default() -> Self {
-        Self {
-            exclude_filename: "actors.json",
-            output_filename: "actors.json",
-            processing_strategy: Box::new(|config, path| process_json_file(&config, path)),
-            large_processing_strategy: Box::new(|config, path| 
-                process_large_json_stream(config, path)
-            ),
-        }
+        ProcessingMode::Standard.processing_config(&EventFilter::default(), None)
+    }
+}
+
+impl ProcessingConfig {
+    /// Opens the outputs of the selected extractors: files in the workspace
+    /// output dir, or the response body for inline jobs
+    fn extractor_set(
+        &self,
+        workspace: &Workspace,
+        options: &ProcessingOptions,
+    ) -> Result<ExtractorSet, AppError> {
+        let fields = options.fields.as_ref();
+        ExtractorSet::create(&options.extractors, &workspace.scratch_dir, |kind| {
+            let (sink, file) = match &options.inline {
+                Some(sender) => (OutputSink::response(sender.clone()), INLINE_OUTPUT.to_string()),
+                None => {
+                    let columns = match fields {
+                        Some(fields) => fields.columns(kind.name(), &kind.columns())?,
+                        None => kind.columns(),
+                    };
+                    let file = self.mode.output_filename(kind.name(), options.format);
+                    let path = workspace.output_dir.join(&file);
+                    (OutputSink::create(&path, options.format, columns)?, file)
+                }
+            };
+            Ok((sink.projected(fields), file))
+        })
+    }
+
+    /// Opens the error report of a job in the workspace output dir
+    fn error_log(&self, workspace: &Workspace) -> ErrorLog {
+        ErrorLog::new(&workspace.output_dir, self.validation)
+    }
+
+    /// Runs the strategy over one file, handing the events that pass the
+    /// filter to `on_event` and the records that are not events to
+    /// `on_invalid`
+    fn parse_file(
+        &self,
+        reader: &mut dyn Read,
+        on_event: &mut dyn FnMut(Event) -> Result<(), AppError>,
+        on_invalid: &mut dyn FnMut(InvalidRecord) -> Result<(), AppError>,
+    ) -> Result<usize, AppError> {
+        let mut on_event = |event: Event| {
+            if self.filter.matches(&event) {
+                on_event(event)
+            } else {
+                METRICS.records_filtered.inc();
+                Ok(())
+            }
+        };
+        (self.processing_strategy)(reader, &mut on_event, on_invalid)
+    }
+
+    /// Runs the strategy over the file `name`, feeding its events to
+    /// `extractors` and its invalid records to `errors`
+    fn process_file(
+        &self,
+        name: &str,
+        reader: &mut dyn Read,
+        extractors: &mut ExtractorSet,
+        errors: &mut ErrorLog,
+    ) -> Result<usize, AppError> {
+        self.parse_file(
+            reader,
+            &mut |event| extractors.extract(&event),
+            &mut |record| errors.record(name, record),
+        )
+    }
+
+    /// Closes the output files and summarizes what was written
+    fn finish(
+        &self,
+        extractors: ExtractorSet,
+        errors: ErrorLog,
+        files_processed: usize,
+    ) -> Result<ProcessingSummary, AppError> {
+        let outputs = extractors.finish()?;
+        let actors = outputs
+            .iter()
+            .find(|output| output.extractor == ExtractorKind::Actors.name())
+            .map_or(0, |output| output.items);
+        METRICS
+            .actors_emitted
+            .with_label_values(&[self.mode.label()])
+            .inc_by(actors as u64);
+
+        Ok(ProcessingSummary {
+            files_processed,
+            actors,
+            outputs,
+            validation: errors.finish()?,
+        })
     }
 }
 
+/// Processes the extracted files of a workspace, parsing up to
+/// `max_parallel_files` of them at the same time.
+///
+/// Files are parsed by worker threads claimed from a limit shared by every
+/// job, see [`FileWorker`]; when none is free the job parses its files on its
+/// own thread, one after the other. Each file being parsed gets its own
+/// bounded channel, opened as the file is handed to a worker, and the
+/// channels are drained into the extractors in filename order. At most one
+/// file per worker is handed out ahead of the one being drained, so outputs
+/// are the same as with sequential processing, while a worker that gets
+/// ahead of the extractors simply waits.
 fn process_directory(
-    config: &AppConfig, 
+    workspace: &Workspace,
     processing_config: &ProcessingConfig,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let nested_actors: Vec<Vec<Actor>> = std::fs::read_dir(Path::new(config.json_dir.as_str()))?
-        .filter_map(|entry| entry.ok())
-        .filter(|entry| 
-            entry.path().file_name().and_then(|s| s.to_str()) 
-            != Some(processing_config.exclude_filename)
-        )
-        .filter(|entry| 
-            entry.path().extension().and_then(|s| s.to_str()) 
-            == Some("json")
-        )
-        .map(|entry| (processing_config.processing_strategy)(&config, &entry.path()))
-        .collect::<Result<Vec<_>, _>>()?;
+    options: &ProcessingOptions,
+) -> Result<ProcessingSummary, AppError> {
+    let mut extractor_set = processing_config.extractor_set(workspace, options)?;
+    let mut errors = processing_config.error_log(workspace);
+    let files = extraction::json_files(&workspace.extract_dir)?;
+    let name = |path: &Path| {
+        let name = path.strip_prefix(&workspace.extract_dir).unwrap_or(path);
+        name.to_string_lossy().into_owned()
+    };
+
+    let workers = match options.max_parallel_files {
+        0 | 1 => Vec::new(),
+        limit => FileWorker::claim(limit.min(files.len()), limit),
+    };
+    if workers.is_empty() {
+        for path in &files {
+            let mut reader = BufReader::new(File::open(path)?);
+            processing_config.process_file(&name(path), &mut reader, &mut extractor_set, &mut errors)?;
+        }
+        return processing_config.finish(extractor_set, errors, files.len());
+    }
+
+    // Handed out in order, so a file is always picked up before the ones
+    // after it and draining the channels in order cannot stall
+    let (work, queue) = mpsc::channel::<(&Path, SyncSender<Result<Parsed, AppError>>)>();
+    let queue = Mutex::new(queue);
 
-    let actors: Vec<Actor> = nested_actors.into_iter().flatten().collect();
+    let window = workers.len();
+    std::thread::scope(|scope| {
+        for worker in workers {
+            let queue = &queue;
+            scope.spawn(move || {
+                // Given back as the thread finishes
+                let _worker = worker;
+                loop {
+                    let Ok((path, sender)) = queue.lock().unwrap().recv() else {
+                        break;
+                    };
+                    if !parse_into(processing_config, path, &sender) {
+                        // The extractors stopped listening after a failure
+                        break;
+                    }
+                }
+            });
+        }
+
+        // Dropping the queue and the open channels on failure stops the
+        // workers
+        let work = work;
+        let mut files = files.iter();
+        let mut hand_out = || {
+            let path = files.next()?;
+            let (sender, receiver) = mpsc::sync_channel(FILE_EVENT_BUFFER);
+            work.send((path.as_path(), sender)).ok()?;
+            Some((path, receiver))
+        };
+        let mut pending: VecDeque<_> = std::iter::from_fn(&mut hand_out).take(window).collect();
+        while let Some((path, receiver)) = pending.pop_front() {
+            pending.extend(hand_out());
+            let name = name(path);
+            for parsed in receiver {
+                match parsed? {
+                    Parsed::Event(event) => extractor_set.extract(&event)?,
+                    Parsed::Invalid(record) => errors.record(&name, record)?,
+                }
+            }
+        }
+        Ok::<_, AppError>(())
+    })?;
+
+    processing_config.finish(extractor_set, errors, files.len())
+}
 
-    let path = PathBuf::from(config.json_dir.to_owned() + processing_config.output_filename);
-    let actors_json = serde_json::to_string(&actors)?;
-    let file = File::create(path)?;
+/// File workers running across all jobs
+static FILE_WORKERS: AtomicUsize = AtomicUsize::new(0);
 
-    let mut writer = BufWriter::new(file);
-    writer.write_all(actors_json.as_bytes())?;
-    writer.flush()?;
+/// Claim on one of the `max_parallel_files` file workers shared by all jobs,
+/// so concurrent jobs do not each start that many threads. Given back when
+/// dropped.
+#[derive(Debug)]
+struct FileWorker;
 
-    Ok(())
+impl FileWorker {
+    /// Claims up to `wanted` workers, as many as are free under `limit`
+    fn claim(wanted: usize, limit: usize) -> Vec<Self> {
+        let mut claimed = Vec::new();
+        while claimed.len() < wanted
+            && FILE_WORKERS
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |running| {
+                    (running < limit).then_some(running + 1)
+                })
+                .is_ok()
+        {
+            claimed.push(Self);
+        }
+        claimed
+    }
 }
 
-fn process_large_directory(
-    config: &AppConfig, 
+impl Drop for FileWorker {
+    fn drop(&mut self) {
+        FILE_WORKERS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Parses one file on a worker, sending its events and invalid records, or
+/// the error that ended it, down `sender`. Returns `false` once nobody is
+/// receiving any more.
+fn parse_into(
     processing_config: &ProcessingConfig,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let nested_actors: Vec<()> = std::fs::read_dir(Path::new(config.large_json_dir.as_str()))?
-        .filter_map(|entry| entry.ok())
-        .filter(|entry| 
-            entry.path().file_name().and_then(|s| s.to_str()) 
-            != Some(processing_config.exclude_filename)
-        )
-        .filter(|entry| 
-            entry.path().extension().and_then(|s| s.to_str()) 
-            == Some("json")
-        )
-        .map(|entry| 
-            (processing_config.large_processing_strategy)(config, &entry.path())
+    path: &Path,
+    sender: &SyncSender<Result<Parsed, AppError>>,
+) -> bool {
+    let connected = Cell::new(true);
+    let send = |parsed: Parsed| {
+        sender.send(Ok(parsed)).map_err(|_| {
+            connected.set(false);
+            AppError::Internal("Processing was stopped".to_string())
+        })
+    };
+    let parsed = File::open(path).map_err(AppError::from).and_then(|file| {
+        let mut reader = BufReader::new(file);
+        processing_config.parse_file(
+            &mut reader,
+            &mut |event| send(Parsed::Event(Box::new(event))),
+            &mut |record| send(Parsed::Invalid(record)),
         )
-        .collect::<Result<Vec<_>, _>>()?;
+    });
+    match parsed {
+        Ok(_) => true,
+        Err(_) if !connected.get() => false,
+        Err(e) => sender.send(Err(e)).is_ok(),
+    }
+}
 
-    println!("{:?}", nested_actors.len());
+pub(crate) fn process_json_dir(
+    workspace: &Workspace,
+    options: &ProcessingOptions,
+) -> Result<ProcessingSummary, AppError> {
+    let processing_config = ProcessingMode::Standard.processing_config(&options.filter, options.validation);
+    process_directory(workspace, &processing_config, options)
+}
 
-    Ok(())
+pub(crate) fn process_large_json_dir(
+    workspace: &Workspace,
+    options: &ProcessingOptions,
+) -> Result<ProcessingSummary, AppError> {
+    let processing_config = ProcessingMode::Large.processing_config(&options.filter, options.validation);
+    process_directory(workspace, &processing_config, options)
 }
 
-pub(crate) fn process_json_dir(
-    config: web::Data<AppConfig>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let processing_config = ProcessingConfig {
-        exclude_filename: "actors.json",
-        output_filename: "actors.json",
-        processing_strategy: Box::new(|config, path| process_json_file(config, path)),
-        large_processing_strategy: Box::new(|config, path| 
-            process_large_json_stream(config, path)
-        ),  
+/// Runs the mode's strategy over each JSON entry of a ZIP archive as it is
+/// read, without extracting anything to disk.
+///
+/// Once an entry turns out to use a data descriptor, the rest of the upload
+/// is spooled and the remaining entries are read through the central
+/// directory, so every entry is still processed exactly once.
+fn process_zip_stream(
+    workspace: &Workspace,
+    mode: ProcessingMode,
+    options: &ProcessingOptions,
+    reader: &mut Rewound<ChunkReader>,
+    limits: ArchiveLimits,
+) -> Result<(ExtractionReport, ProcessingSummary), AppError> {
+    let processing_config = mode.processing_config(&options.filter, options.validation);
+    let mut extractor_set = processing_config.extractor_set(workspace, options)?;
+    let mut errors = processing_config.error_log(workspace);
+    let mut budget = ArchiveBudget::new(limits);
+
+    let mut on_entry = |name: &str, entry: &mut dyn Read| {
+        let mut entry = BufReader::new(entry);
+        let name = compression::decompressed_name(name);
+        processing_config.process_file(name, &mut entry, &mut extractor_set, &mut errors)?;
+        Ok(())
+    };
+
+    let report = match zip_stream::stream_zip_entries(reader, &mut budget, &mut on_entry)? {
+        StreamOutcome::Completed(report) => report,
+        StreamOutcome::NeedsCentralDirectory { entries_read, mut report } => {
+            tracing::info!(
+                "Job {} uses data descriptors, reading the entries after the first {} through the central directory",
+                workspace.job_id,
+                entries_read
+            );
+            reader.get_mut().1.drain()?;
+            let archive = BufReader::new(File::open(&workspace.upload_path)?);
+            zip_stream::resume_zip_entries(archive, &mut budget, &mut report, entries_read, &mut on_entry)?;
+            report
+        }
     };
-    process_directory(&config, &processing_config)
+
+    let summary = processing_config.finish(extractor_set, errors, report.files_extracted)?;
+    Ok((report, summary))
 }
 
-pub(crate) fn process_large_json_dir(
-    config: web::Data<AppConfig>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let processing_config = ProcessingConfig {
-        exclude_filename: "actors-stream.json",
-        output_filename: "actors-stream.json",
-        processing_strategy: Box::new(|config, path| process_json_file(config, path)),
-        large_processing_strategy: Box::new(|config, path| 
-            process_large_json_stream(config, path)
-        ),
+/// Runs the mode's strategy over each JSON entry of a tar archive as it is
+/// read, without extracting anything to disk. A compressed tarball is
+/// decompressed on the way and held to the compression ratio as a whole.
+fn process_tar_stream(
+    workspace: &Workspace,
+    mode: ProcessingMode,
+    options: &ProcessingOptions,
+    compression: Compression,
+    reader: impl Read,
+    limits: ArchiveLimits,
+) -> Result<(ExtractionReport, ProcessingSummary), AppError> {
+    let processing_config = mode.processing_config(&options.filter, options.validation);
+    let mut extractor_set = processing_config.extractor_set(workspace, options)?;
+    let mut errors = processing_config.error_log(workspace);
+    let mut budget = ArchiveBudget::new(limits);
+    let mut report = ExtractionReport::default();
+    let reader = compression.decoder(budget.count_compressed(reader))?;
+
+    file_processing::for_each_tar_entry(reader, &mut budget, &mut report, |budget, report, name, size, entry| {
+        let Some(json) = compression::json_entry(name, entry) else {
+            return Ok(());
+        };
+        let mut entry = BufReader::new(budget.limit_entry(name, size, size, json));
+        let name = compression::decompressed_name(name);
+        processing_config.process_file(name, &mut entry, &mut extractor_set, &mut errors)?;
+        report.files_extracted += 1;
+        Ok(())
+    })?;
+
+    let summary = processing_config.finish(extractor_set, errors, report.files_extracted)?;
+    Ok((report, summary))
+}
+
+/// Runs the mode's strategy over an upload that is a single compressed file,
+/// decompressing it as it is read and holding it to the compression ratio
+/// against the bytes received so far
+fn process_compressed_stream(
+    workspace: &Workspace,
+    mode: ProcessingMode,
+    options: &ProcessingOptions,
+    compression: Compression,
+    reader: impl Read,
+    limits: ArchiveLimits,
+) -> Result<(ExtractionReport, ProcessingSummary), AppError> {
+    let processing_config = mode.processing_config(&options.filter, options.validation);
+    let mut extractor_set = processing_config.extractor_set(workspace, options)?;
+    let mut errors = processing_config.error_log(workspace);
+    let mut budget = ArchiveBudget::new(limits);
+    let decoder = compression.decoder(budget.count_compressed(reader))?;
+
+    let mut entry = BufReader::new(budget.limit_entry(COMPRESSED_UPLOAD_ENTRY, 0, 0, decoder));
+    processing_config.process_file(COMPRESSED_UPLOAD_ENTRY, &mut entry, &mut extractor_set, &mut errors)?;
+
+    let report = ExtractionReport {
+        files_extracted: 1,
+        ..Default::default()
+    };
+    let summary = processing_config.finish(extractor_set, errors, report.files_extracted)?;
+    Ok((report, summary))
+}
+
+/// Processes an upload while its chunks are still arriving.
+///
+/// Entries are parsed straight from the request body, whatever the archive
+/// format, and compressed uploads are decompressed on the fly. If a ZIP
+/// archive turns out to use data descriptors, the entries from there on are
+/// read from the spooled upload instead.
+pub(crate) fn process_upload_stream(
+    workspace: &Workspace,
+    mode: ProcessingMode,
+    options: &ProcessingOptions,
+    chunks: ChunkReceiver,
+    limits: ArchiveLimits,
+) -> Result<(ExtractionReport, ProcessingSummary), AppError> {
+    // Extraction and parsing overlap here, so this covers the whole read
+    let _timer = METRICS.extraction_duration.start_timer();
+    let spool = File::create(&workspace.upload_path)?;
+    let (format, mut reader) = ArchiveFormat::sniff(ChunkReader::new(chunks, spool))?;
+
+    let streamed = match format {
+        Some(ArchiveFormat::Tar(compression)) => {
+            process_tar_stream(workspace, mode, options, compression, &mut reader, limits)?
+        }
+        Some(ArchiveFormat::Compressed(compression)) => {
+            process_compressed_stream(workspace, mode, options, compression, &mut reader, limits)?
+        }
+        Some(ArchiveFormat::Zip) | None => process_zip_stream(workspace, mode, options, &mut reader, limits)?,
     };
-    process_large_directory(&config, &processing_config)
+    reader.get_mut().1.drain()?;
+    Ok(streamed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Workspace with `files` JSON files of a few events each, written in
+    /// reverse filename order; `broken` lines are added to every third file
+    fn workspace(dir: &Path, files: usize, broken: bool) -> Workspace {
+        let root = dir.to_string_lossy();
+        let (uploads, json) = (format!("{}/uploads", root), format!("{}/json", root));
+        let workspace = Workspace::create(&uploads, &json, "upload.zip").unwrap();
+        for file in (0..files).rev() {
+            let path = workspace.extract_dir.join(format!("part-{:02}/events.json", file));
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let mut out = File::create(path).unwrap();
+            for event in 0..50 {
+                if broken && file % 3 == 0 && event == 10 {
+                    writeln!(out, "{{\"id\": ").unwrap();
+                }
+                let login = format!("user-{}-{}", file, event);
+                let event = serde_json::json!({"id": event.to_string(), "actor": {"login": login}, "public": true});
+                writeln!(out, "{}", event).unwrap();
+            }
+        }
+        workspace
+    }
+
+    fn options(max_parallel_files: usize) -> ProcessingOptions {
+        let kinds = vec![ExtractorKind::Actors];
+        let mut options = ProcessingOptions::from_config(&AppConfig::default(), kinds, OutputFormat::Ndjson);
+        options.max_parallel_files = max_parallel_files;
+        options
+    }
+
+    fn run(workspace: &Workspace, mode: ProcessingMode, max_parallel_files: usize) -> (ProcessingSummary, String) {
+        let options = options(max_parallel_files);
+        let summary = mode.run(workspace, &options).unwrap();
+        let output = workspace.output_dir.join(mode.output_filename("actors", OutputFormat::Ndjson));
+        (summary, std::fs::read_to_string(output).unwrap())
+    }
+
+    #[test]
+    fn parallel_output_is_in_filename_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = workspace(dir.path(), 12, false);
+
+        let (sequential, expected) = run(&workspace, ProcessingMode::Standard, 1);
+        assert_eq!(sequential.files_processed, 12);
+        assert_eq!(sequential.actors, 600);
+        let logins: Vec<_> = expected
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["login"].clone())
+            .collect();
+        assert_eq!(logins[0], "user-0-0");
+        assert_eq!(logins[50], "user-1-0");
+        assert_eq!(logins[599], "user-11-49");
+
+        for workers in [2, 4, 32] {
+            let (summary, output) = run(&workspace, ProcessingMode::Standard, workers);
+            assert_eq!(summary.actors, 600);
+            assert_eq!(output, expected, "with {} workers", workers);
+        }
+    }
+
+    #[test]
+    fn every_mode_writes_the_union_of_all_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = workspace(dir.path(), 3, false);
+
+        for mode in [ProcessingMode::Standard, ProcessingMode::Large] {
+            let (summary, output) = run(&workspace, mode, 1);
+            assert_eq!(summary.files_processed, 3);
+            assert_eq!(output.lines().count(), 150, "{:?}", mode);
+            for file in 0..3 {
+                assert!(output.contains(&format!("\"user-{}-49\"", file)), "{:?} lost file {}", mode, file);
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_records_are_reported_in_file_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = workspace(dir.path(), 7, true);
+
+        let (summary, _) = run(&workspace, ProcessingMode::Large, 4);
+        assert_eq!(summary.actors, 350);
+        let files: Vec<_> = summary.validation.errors.iter().map(|error| error.file.as_str()).collect();
+        assert_eq!(files, ["part-00/events.json", "part-03/events.json", "part-06/events.json"]);
+        assert!(summary.validation.errors.iter().all(|error| error.index == 10));
+    }
+
+    #[test]
+    fn the_first_invalid_record_fails_a_parallel_standard_job() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = workspace(dir.path(), 7, true);
+        let error = ProcessingMode::Standard.run(&workspace, &options(4)).unwrap_err();
+        assert!(
+            matches!(&error, AppError::Processing(message) if message.contains("part-00/events.json")),
+            "{:?}",
+            error
+        );
+    }
 }
@@ -0,0 +1,68 @@
+use crate::config::AppConfig;
+use crate::error::AppError;
+use crate::utils::output::NDJSON_CONTENT_TYPE;
+use crate::workspace::Workspace;
+use actix_files::NamedFile;
+use actix_web::http::header::ContentType;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Content types for the artifacts `mime_guess` does not know about
+fn content_type(artifact: &Path) -> Option<ContentType> {
+    match artifact.extension()?.to_str()? {
+        "ndjson" => Some(ContentType(NDJSON_CONTENT_TYPE.parse().ok()?)),
+        "csv" => Some(ContentType("text/csv; charset=utf-8".parse().ok()?)),
+        "parquet" => Some(ContentType("application/vnd.apache.parquet".parse().ok()?)),
+        _ => None,
+    }
+}
+
+/// Finds an artifact produced by a job, whichever mode it ran in. Only plain
+/// file names are accepted so the path cannot leave the job's output dir.
+fn artifact_path(config: &AppConfig, job_id: &Uuid, artifact: &str) -> Option<PathBuf> {
+    let is_plain_name = Path::new(artifact).file_name().and_then(|name| name.to_str())
+        == Some(artifact)
+        && !artifact.starts_with('.');
+    if !is_plain_name {
+        return None;
+    }
+
+    [&config.json_dir, &config.large_json_dir]
+        .into_iter()
+        .map(|json_dir| Workspace::output_dir(json_dir, job_id).join(artifact))
+        .find(|path| path.is_file())
+}
+
+/// Serves a file a job wrote to its output dir, such as `actors.json`.
+///
+/// NamedFile answers conditional requests from the ETag and Last-Modified it
+/// sets and serves `Range` requests as partial content, so interrupted
+/// downloads can be resumed. Compression is left to the middleware wrapping
+/// the `/results` scope.
+#[get("/{job_id}/{artifact}")]
+pub async fn download_result(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    path: web::Path<(Uuid, String)>,
+) -> Result<HttpResponse, AppError> {
+    let (job_id, artifact) = path.into_inner();
+    let Some(file_path) = artifact_path(&config, &job_id, &artifact) else {
+        return Err(AppError::NotFound(format!(
+            "Result {} of job {} not found",
+            artifact, job_id
+        )));
+    };
+
+    Ok(open_artifact(&file_path).await?.into_response(&req))
+}
+
+/// Opens an output file to be streamed from disk in chunks, with the
+/// content type of its format
+pub(super) async fn open_artifact(path: &Path) -> Result<NamedFile, AppError> {
+    let file = NamedFile::open_async(path).await?;
+    Ok(match content_type(path) {
+        Some(content_type) => file.set_content_type(content_type.0),
+        None => file,
+    })
+}
@@ -1,76 +1,420 @@
-use std::path::PathBuf;
-
 use crate::config::AppConfig;
-use crate::handlers::processing;
-use crate::utils::file_processing;
+use crate::error::AppError;
+use crate::handlers::processing::{self, ProcessingMode, ProcessingOptions, ProcessingSummary};
+use crate::jobs::{self, JobStore};
+use crate::metrics::InFlightJob;
+use crate::utils::blocking_pool::{BlockingPool, PoolError, Reservation};
+use crate::utils::event_query::{EventFilter, Projection};
+use crate::utils::extraction::ExtractionReport;
+use crate::utils::extractors::ExtractorKind;
+use crate::utils::file_processing::{self, UploadForm};
+use crate::utils::limits::ArchiveLimits;
+use crate::utils::output::{OutputFormat, NDJSON_CONTENT_TYPE};
+use crate::utils::time_series::TimeBucket;
+use crate::utils::validation::ValidationPolicy;
+use crate::utils::zip_stream::{self, CHUNK_BUFFER};
+use crate::workspace::{Workspace, WorkspaceGuard};
 use actix_multipart::Multipart;
-use actix_web::{post, web, Error, HttpResponse};
+use actix_web::http::header;
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use futures::channel::{mpsc, oneshot};
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
 use serde_json::json;
+use uuid::Uuid;
 
-async fn handle_upload(
-    config: web::Data<AppConfig>,
+/// Query parameters that select what a job extracts and how, accepted by
+/// every upload endpoint
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProcessingQuery {
+    /// Comma-separated extractors to run, e.g. `actors,repos`
+    pub extract: Option<String>,
+    /// Format of the output files: `json` (default), `ndjson`, `csv` or
+    /// `parquet`
+    pub format: Option<String>,
+    /// Comma-separated fields kept in the output records, e.g. `id,login`
+    pub fields: Option<String>,
+    /// Conditions events must meet, separated by `;`, e.g.
+    /// `type=PushEvent;repo.name~^rust-lang/`. A `;` within a condition is
+    /// written `\;`.
+    pub filter: Option<String>,
+    /// Bucket width of the time series extractors: `hour` (default) or `day`
+    pub bucket: Option<String>,
+    /// What happens to records that are not valid events: `fail_fast`,
+    /// `skip` or `quarantine`. Defaults to `fail_fast` for `/upload` and
+    /// `skip` for `/upload_large`.
+    pub validation: Option<String>,
+}
+
+/// Query parameters accepted by the synchronous upload endpoints
+#[derive(Debug, Default, Deserialize)]
+pub struct UploadOptions {
+    #[serde(flatten)]
+    pub processing: ProcessingQuery,
+    /// Parse archive entries while the body is still arriving instead of
+    /// saving and extracting the whole archive first
+    #[serde(default)]
+    pub stream: bool,
+    /// Stream the extracted records back as NDJSON instead of a summary.
+    /// Also selected by `Accept: application/x-ndjson`.
+    #[serde(default)]
+    pub inline: bool,
+}
+
+/// Response chunks buffered between processing and the client
+const INLINE_CHUNK_BUFFER: usize = 8;
+
+/// Builds the processing options of a job, picking the extractors from the
+/// query parameter and falling back to the form field. `inline` requests are
+/// also held to what a response body can carry.
+fn processing_options(
+    config: &AppConfig,
+    query: &ProcessingQuery,
+    form: &UploadForm,
+    inline: bool,
+) -> Result<ProcessingOptions, AppError> {
+    let kinds = ExtractorKind::parse_list(query.extract.as_deref().or(form.extract.as_deref()))?;
+    let fields = Projection::parse(query.fields.as_deref());
+    // Checked up front so a bad field is rejected before any work is done
+    if let Some(fields) = &fields {
+        for kind in &kinds {
+            fields.columns(kind.name(), &kind.columns())?;
+        }
+    }
+
+    let mut options =
+        ProcessingOptions::from_config(config, kinds, OutputFormat::parse(query.format.as_deref())?);
+    options.filter = EventFilter::parse(query.filter.as_deref())?;
+    options.extractors.bucket = TimeBucket::parse(query.bucket.as_deref())?;
+    options.fields = fields;
+    options.validation = ValidationPolicy::parse(query.validation.as_deref())?;
+
+    if inline {
+        if options.extractors.kinds.len() != 1 {
+            return Err(AppError::InvalidRequest(
+                "Inline responses carry a single extractor".to_string(),
+            ));
+        }
+        if !matches!(options.format, OutputFormat::Json | OutputFormat::Ndjson) {
+            return Err(AppError::InvalidRequest(
+                "Inline responses are always NDJSON, omit format or use format=ndjson".to_string(),
+            ));
+        }
+    }
+    Ok(options)
+}
+
+/// Logs a failed upload against its job before it is turned into a response
+fn job_error(job_id: Uuid, error: AppError) -> AppError {
+    tracing::warn!("Upload error for job {}: {}", job_id, error);
+    error
+}
+
+/// Gives an upload its own job ID and directory tree, in the directories of
+/// its mode. The tree is deleted again unless the upload is kept.
+fn create_workspace(config: &AppConfig, mode: ProcessingMode) -> Result<WorkspaceGuard, AppError> {
+    let (upload_dir, json_dir) = mode.dirs(config);
+    Ok(WorkspaceGuard::new(Workspace::create(upload_dir, json_dir, &config.upload_file_name)?))
+}
+
+/// Creates a workspace for the upload and writes the multipart body into it
+async fn receive_upload(
+    config: &AppConfig,
     payload: Multipart,
-    process_fn: impl Fn(web::Data<AppConfig>) -> Result<(), Box<dyn std::error::Error>>,
-    is_large_upload: bool,
-) -> Result<HttpResponse, Error> {
-    // Choose appropriate directories based on upload type
-    let (upload_dir, json_dir) = if is_large_upload {
-        (&config.large_upload_dir, &config.large_json_dir)
-    } else {
-        (&config.upload_dir, &config.json_dir)
-    };
-
-    // Create directories if they don't exist
-    std::fs::create_dir_all(upload_dir)?;
-    std::fs::create_dir_all(json_dir)?;
-
-    // Create file path using the chosen upload directory
-    let file_path = PathBuf::from(upload_dir.to_owned() + &config.upload_file_name);
+    mode: ProcessingMode,
+) -> Result<(WorkspaceGuard, UploadForm), AppError> {
+    let workspace = create_workspace(config, mode)?;
 
     let file = std::fs::OpenOptions::new()
         .write(true)
         .create(true)
         .truncate(true)
-        .open(&file_path)?;
+        .open(&workspace.upload_path)?;
 
-    file_processing::save_multipart_file(payload, file)
+    let limits = ArchiveLimits::from_config(config);
+    let form = file_processing::save_multipart_file(payload, file, &limits)
         .await
-        .map_err(actix_web::error::ErrorBadRequest)?;
-
-    // Create a new config with the appropriate directories for processing
-    let processing_config = AppConfig {
-        upload_dir: upload_dir.clone(),
-        json_dir: json_dir.clone(),
-        ..config.as_ref().clone()
-    };
-
-    match file_processing::validate_and_uncompress_zip(&processing_config, &file_path).await {
-        Ok(_) => {
-            process_fn(web::Data::new(processing_config))
-                .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?;
-            Ok(HttpResponse::Ok().json(json!({"status": "ok"})))
-        }
-        Err(e) => {
-            tracing::error!("Zip validation error: {}", e);
-            Ok(HttpResponse::BadRequest().json(json!({
-                "error": e.to_string()
-            })))
+        .map_err(|e| job_error(workspace.job_id, e))?;
+
+    Ok((workspace, form))
+}
+
+/// Whether the client asked for the records themselves as NDJSON
+fn wants_inline(req: &HttpRequest, options: &UploadOptions) -> bool {
+    options.inline
+        || req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains(NDJSON_CONTENT_TYPE))
+}
+
+async fn handle_upload(
+    config: web::Data<AppConfig>,
+    jobs: web::Data<JobStore>,
+    pool: web::Data<BlockingPool>,
+    payload: Multipart,
+    mode: ProcessingMode,
+    options: UploadOptions,
+) -> Result<HttpResponse, AppError> {
+    if options.inline && options.stream {
+        return Err(AppError::InvalidRequest(
+            "Inline responses cannot be combined with stream=true".to_string(),
+        ));
+    }
+    let in_flight = InFlightJob::start();
+    if options.stream {
+        let processing = processing_options(&config, &options.processing, &UploadForm::default(), false)?;
+        return handle_streaming_upload(config, jobs, pool, payload, mode, processing).await;
+    }
+
+    // Checked before the body is read, so a bad request is turned away
+    // without uploading the archive first
+    let query = &options.processing;
+    let mut processing = processing_options(&config, query, &UploadForm::default(), options.inline)?;
+    // Extraction and processing then run as one task in this place, so an
+    // upload that has been received is never turned away as busy
+    let reservation = pool.reserve()?;
+    let (workspace, form) = receive_upload(&config, payload, mode).await?;
+    if form.extract.is_some() {
+        processing = processing_options(&config, query, &form, options.inline)?;
+    }
+    let limits = ArchiveLimits::from_config(&config);
+
+    if options.inline {
+        return inline_response(jobs, reservation, workspace, mode, processing, limits, in_flight).await;
+    }
+
+    let task_workspace = workspace.clone();
+    let task_options = processing.clone();
+    let (report, summary) = reservation
+        .run(move || {
+            let processed = file_processing::validate_and_extract_archive(&task_workspace, limits)
+                .and_then(|report| Ok((report, mode.run(&task_workspace, &task_options)?)));
+            jobs::remove_inputs(&task_workspace);
+            processed
+        })
+        .await?
+        .map_err(|e| job_error(workspace.job_id, e))?;
+
+    Ok(finished_upload(&jobs, workspace, mode, &processing, report, summary))
+}
+
+/// Hands a processed upload to the job store, which deletes its output once
+/// it expires, and answers with the processing summary
+fn finished_upload(
+    jobs: &JobStore,
+    workspace: WorkspaceGuard,
+    mode: ProcessingMode,
+    options: &ProcessingOptions,
+    report: ExtractionReport,
+    summary: ProcessingSummary,
+) -> HttpResponse {
+    let workspace = workspace.keep();
+    let response = HttpResponse::Ok().json(json!({
+        "status": "ok",
+        "job_id": workspace.job_id,
+        "summary": summary,
+        "extraction": report,
+    }));
+    jobs.insert_finished(&workspace, mode, options, report, summary);
+    response
+}
+
+/// Extracts an upload, then processes it while its records are streamed
+/// back as a chunked NDJSON response.
+///
+/// The response starts once the archive has been extracted, so extraction
+/// errors still get their own status code. A failure past that point cannot
+/// change it; it is reported as a last line holding `{"error": {...}}`
+/// instead.
+async fn inline_response(
+    jobs: web::Data<JobStore>,
+    reservation: Reservation,
+    workspace: WorkspaceGuard,
+    mode: ProcessingMode,
+    mut processing: ProcessingOptions,
+    limits: ArchiveLimits,
+    in_flight: InFlightJob,
+) -> Result<HttpResponse, AppError> {
+    let job_id = workspace.job_id;
+    let (sender, receiver) = mpsc::channel(INLINE_CHUNK_BUFFER);
+    let mut errors = sender.clone();
+    // Registered without the sender, which would keep the response open
+    let registered = processing.clone();
+    processing.inline = Some(sender);
+
+    let (extracted, extraction) = oneshot::channel();
+    let task_workspace = workspace.clone();
+    let task = reservation.run(move || {
+        let processed = match file_processing::validate_and_extract_archive(&task_workspace, limits) {
+            Ok(report) => {
+                let _ = extracted.send(Ok(()));
+                Some(mode.run(&task_workspace, &processing).map(|summary| (report, summary)))
+            }
+            // Answered by the handler, which is waiting for extraction
+            Err(e) => {
+                let _ = extracted.send(Err(e));
+                None
+            }
+        };
+        jobs::remove_inputs(&task_workspace);
+        processed
+    });
+
+    actix_web::rt::spawn(async move {
+        let _in_flight = in_flight;
+        let e = match task.await {
+            Ok(Some(Ok((report, summary)))) => {
+                jobs.insert_finished(&workspace.keep(), mode, &registered, report, summary);
+                return;
+            }
+            Ok(None) => return,
+            Ok(Some(Err(e))) => e,
+            Err(e) => e.into(),
+        };
+        // Writing to the response failed because the client went away,
+        // which is not an error of the job
+        if errors.is_closed() {
+            tracing::debug!("Client of job {} disconnected, processing stopped", job_id);
+            return;
         }
+        let e = job_error(job_id, e);
+        let mut line = serde_json::to_vec(&json!({ "error": e.body() })).unwrap_or_default();
+        line.push(b'\n');
+        // Nothing is left to tell if the client has gone away
+        let _ = errors.send(Bytes::from(line)).await;
+    });
+
+    extraction
+        .await
+        .map_err(|_| AppError::from(PoolError::Canceled))?
+        .map_err(|e| job_error(job_id, e))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(NDJSON_CONTENT_TYPE)
+        .insert_header(("X-Job-Id", job_id.to_string()))
+        .streaming(receiver.map(Ok::<_, actix_web::Error>)))
+}
+
+/// Feeds the multipart body into the ZIP stream reader running on the pool
+async fn handle_streaming_upload(
+    config: web::Data<AppConfig>,
+    jobs: web::Data<JobStore>,
+    pool: web::Data<BlockingPool>,
+    payload: Multipart,
+    mode: ProcessingMode,
+    options: ProcessingOptions,
+) -> Result<HttpResponse, AppError> {
+    let workspace = create_workspace(&config, mode)?;
+
+    let limits = ArchiveLimits::from_config(&config);
+    let (sender, receiver) = mpsc::channel(CHUNK_BUFFER);
+    let stream_workspace = workspace.clone();
+    let stream_options = options.clone();
+    let processing = pool.run(move || {
+        let processed =
+            processing::process_upload_stream(&stream_workspace, mode, &stream_options, receiver, limits);
+        jobs::remove_inputs(&stream_workspace);
+        processed
+    });
+
+    let (forwarded, processed) = futures::join!(
+        zip_stream::forward_multipart(payload, sender, &limits),
+        processing
+    );
+    // A failed upload explains a failed reader, so it is reported first
+    forwarded.map_err(|e| job_error(workspace.job_id, e))?;
+    let (report, summary) = processed?.map_err(|e| job_error(workspace.job_id, e))?;
+
+    Ok(finished_upload(&jobs, workspace, mode, &options, report, summary))
+}
+
+/// Stores the upload and hands it to a background job, answering `202 Accepted`
+async fn submit_job(
+    config: web::Data<AppConfig>,
+    jobs: web::Data<JobStore>,
+    pool: web::Data<BlockingPool>,
+    payload: Multipart,
+    mode: ProcessingMode,
+    options: ProcessingQuery,
+) -> Result<HttpResponse, AppError> {
+    // Hold a place in the queue before reading the body, so a job that is
+    // accepted is never turned away as busy afterwards
+    let reservation = pool.reserve()?;
+
+    // Checked before the body is read, like the synchronous uploads do
+    let mut processing = processing_options(&config, &options, &UploadForm::default(), false)?;
+    let (workspace, form) = receive_upload(&config, payload, mode).await?;
+    if form.extract.is_some() {
+        // Dropping the guard on a bad form field deletes the stored upload
+        processing = processing_options(&config, &options, &form, false)?;
     }
+    // From here on the job store deletes the workspace once the job expires
+    let workspace = workspace.keep();
+    let status = jobs.insert(&workspace, mode, &processing);
+
+    actix_web::rt::spawn(jobs::run_job(
+        jobs.get_ref().clone(),
+        reservation,
+        workspace,
+        mode,
+        processing,
+        ArchiveLimits::from_config(&config),
+    ));
+
+    Ok(HttpResponse::Accepted()
+        .insert_header(("Location", format!("/jobs/{}", status.job_id)))
+        .json(status))
 }
 
 #[post("/upload")]
 pub async fn upload_zip(
+    req: HttpRequest,
     config: web::Data<AppConfig>,
+    jobs: web::Data<JobStore>,
+    pool: web::Data<BlockingPool>,
+    options: web::Query<UploadOptions>,
     payload: Multipart,
-) -> Result<HttpResponse, Error> {
-    handle_upload(config, payload, processing::process_json_dir, false).await
+) -> Result<HttpResponse, AppError> {
+    let mut options = options.into_inner();
+    options.inline = wants_inline(&req, &options);
+    handle_upload(config, jobs, pool, payload, ProcessingMode::Standard, options).await
 }
 
 #[post("/upload_large")]
 pub async fn upload_large_zip(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    jobs: web::Data<JobStore>,
+    pool: web::Data<BlockingPool>,
+    options: web::Query<UploadOptions>,
+    payload: Multipart,
+) -> Result<HttpResponse, AppError> {
+    let mut options = options.into_inner();
+    options.inline = wants_inline(&req, &options);
+    handle_upload(config, jobs, pool, payload, ProcessingMode::Large, options).await
+}
+
+#[post("/jobs/upload")]
+pub async fn submit_upload_job(
+    config: web::Data<AppConfig>,
+    jobs: web::Data<JobStore>,
+    pool: web::Data<BlockingPool>,
+    options: web::Query<ProcessingQuery>,
+    payload: Multipart,
+) -> Result<HttpResponse, AppError> {
+    submit_job(config, jobs, pool, payload, ProcessingMode::Standard, options.into_inner()).await
+}
+
+#[post("/jobs/upload_large")]
+pub async fn submit_upload_large_job(
     config: web::Data<AppConfig>,
+    jobs: web::Data<JobStore>,
+    pool: web::Data<BlockingPool>,
+    options: web::Query<ProcessingQuery>,
     payload: Multipart,
-) -> Result<HttpResponse, Error> {
-    handle_upload(config, payload, processing::process_large_json_dir, true).await
+) -> Result<HttpResponse, AppError> {
+    submit_job(config, jobs, pool, payload, ProcessingMode::Large, options.into_inner()).await
 }
\ No newline at end of file
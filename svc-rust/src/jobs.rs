@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::config::AppConfig;
+use crate::error::{AppError, ErrorBody};
+use crate::handlers::processing::{ProcessingMode, ProcessingOptions, ProcessingSummary};
+use crate::metrics::InFlightJob;
+use crate::utils::blocking_pool::Reservation;
+use crate::utils::extraction::{ExtractionReport, SkippedEntry};
+use crate::utils::extractors::{ExtractorKind, ExtractorOutput};
+use crate::utils::file_processing;
+use crate::utils::limits::ArchiveLimits;
+use crate::utils::output::OutputFormat;
+use crate::utils::validation::ValidationReport;
+use crate::workspace::{self, Workspace};
+
+/// Lifecycle of an asynchronous upload job
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Extracting,
+    Processing,
+    Done,
+    Failed,
+}
+
+/// Progress counters reported while a job runs
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct JobCounts {
+    pub files_extracted: usize,
+    pub files_processed: usize,
+    pub actors: usize,
+}
+
+/// Snapshot of a job as returned by `GET /jobs/{id}`
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub job_id: Uuid,
+    pub mode: ProcessingMode,
+    pub extractors: Vec<ExtractorKind>,
+    pub format: OutputFormat,
+    pub state: JobState,
+    pub counts: JobCounts,
+    /// Archive entries that were not extracted
+    pub skipped: Vec<SkippedEntry>,
+    /// Files written by the extractors once the job is done
+    pub outputs: Vec<ExtractorOutput>,
+    /// Records left out because they could not be read as events, once the
+    /// job is done
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub validation: Option<ValidationReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorBody>,
+    /// Where the output of the first selected extractor lives once the job
+    /// is done
+    #[serde(skip)]
+    pub result_path: PathBuf,
+}
+
+/// How often finished jobs are checked for expiry, besides on every insert
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A job as the store keeps it, with what is needed to clean up after it
+#[derive(Debug, Clone)]
+struct JobEntry {
+    status: JobStatus,
+    workspace: Workspace,
+    /// When the job reached `done` or `failed`
+    finished_at: Option<Instant>,
+}
+
+/// In-memory registry of submitted jobs, shared by all workers.
+///
+/// Finished jobs are kept for `ttl`, and once the store holds `capacity`
+/// jobs the oldest finished ones make room for new ones. An evicted job's
+/// workspace is deleted with it. Running jobs are never evicted.
+#[derive(Debug, Clone)]
+pub struct JobStore {
+    jobs: Arc<RwLock<HashMap<Uuid, JobEntry>>>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl JobStore {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            jobs: Arc::default(),
+            ttl,
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn from_config(config: &AppConfig) -> Self {
+        Self::new(Duration::from_secs(config.job_ttl_secs), config.max_jobs)
+    }
+
+    /// Registers a new job for the given workspace in the `queued` state
+    pub fn insert(
+        &self,
+        workspace: &Workspace,
+        mode: ProcessingMode,
+        options: &ProcessingOptions,
+    ) -> JobStatus {
+        let extractors = &options.extractors.kinds;
+        let primary = extractors.first().copied().unwrap_or(ExtractorKind::Actors);
+        let status = JobStatus {
+            job_id: workspace.job_id,
+            mode,
+            extractors: extractors.to_vec(),
+            format: options.format,
+            state: JobState::Queued,
+            counts: JobCounts::default(),
+            skipped: Vec::new(),
+            outputs: Vec::new(),
+            validation: None,
+            error: None,
+            result_path: workspace.output_dir.join(mode.output_filename(primary.name(), options.format)),
+        };
+        self.evict();
+        self.jobs.write().unwrap().insert(
+            status.job_id,
+            JobEntry {
+                status: status.clone(),
+                workspace: workspace.clone(),
+                finished_at: None,
+            },
+        );
+        status
+    }
+
+    /// Registers an upload that was processed while the client waited, so
+    /// its results can be looked up and expire like those of a job
+    pub(crate) fn insert_finished(
+        &self,
+        workspace: &Workspace,
+        mode: ProcessingMode,
+        options: &ProcessingOptions,
+        report: ExtractionReport,
+        summary: ProcessingSummary,
+    ) {
+        let job_id = self.insert(workspace, mode, options).job_id;
+        self.extracted(&job_id, report);
+        self.complete(&job_id, summary);
+    }
+
+    pub fn get(&self, job_id: &Uuid) -> Option<JobStatus> {
+        self.jobs.read().unwrap().get(job_id).map(|entry| entry.status.clone())
+    }
+
+    fn update(&self, job_id: &Uuid, f: impl FnOnce(&mut JobStatus)) {
+        if let Some(entry) = self.jobs.write().unwrap().get_mut(job_id) {
+            f(&mut entry.status);
+            if matches!(entry.status.state, JobState::Done | JobState::Failed) {
+                entry.finished_at.get_or_insert_with(Instant::now);
+            }
+        }
+    }
+
+    /// Records what was extracted from a job's archive, as processing starts
+    fn extracted(&self, job_id: &Uuid, report: ExtractionReport) {
+        self.update(job_id, |status| {
+            status.state = JobState::Processing;
+            status.counts.files_extracted = report.files_extracted;
+            status.skipped = report.skipped;
+        });
+    }
+
+    fn complete(&self, job_id: &Uuid, summary: ProcessingSummary) {
+        self.update(job_id, |status| {
+            status.state = JobState::Done;
+            status.counts.files_processed = summary.files_processed;
+            status.counts.actors = summary.actors;
+            status.outputs = summary.outputs;
+            status.validation = Some(summary.validation);
+        });
+    }
+
+    fn fail(&self, job_id: &Uuid, error: AppError) {
+        tracing::error!("Job {} failed: {}", job_id, error);
+        self.update(job_id, |status| {
+            status.state = JobState::Failed;
+            status.error = Some(error.body());
+        });
+    }
+
+    /// Drops finished jobs past their TTL, then the oldest finished jobs
+    /// while the store is at capacity, and deletes their workspaces in the
+    /// background
+    pub fn evict(&self) {
+        let evicted = {
+            let mut jobs = self.jobs.write().unwrap();
+            let mut finished: Vec<(Instant, Uuid)> = jobs
+                .iter()
+                .filter_map(|(job_id, entry)| Some((entry.finished_at?, *job_id)))
+                .collect();
+            finished.sort();
+
+            let mut evicted = Vec::new();
+            for (finished_at, job_id) in finished {
+                if finished_at.elapsed() < self.ttl && jobs.len() < self.capacity {
+                    break;
+                }
+                if let Some(entry) = jobs.remove(&job_id) {
+                    evicted.push(entry.workspace);
+                }
+            }
+            evicted
+        };
+        if evicted.is_empty() {
+            return;
+        }
+
+        tracing::debug!("Evicting {} finished jobs", evicted.len());
+        workspace::remove_in_background(evicted);
+    }
+}
+
+/// Evicts expired jobs every [`EVICTION_INTERVAL`], so their files go away
+/// even when no new jobs come in
+pub async fn evict_periodically(store: JobStore) {
+    let mut interval = actix_web::rt::time::interval(EVICTION_INTERVAL);
+    loop {
+        interval.tick().await;
+        store.evict();
+    }
+}
+
+/// Extracts and processes an uploaded archive, recording progress in the
+/// store.
+///
+/// The whole job runs as one task in the place reserved for it before the
+/// upload was accepted, so it cannot be turned away once it is queued.
+pub async fn run_job(
+    store: JobStore,
+    reservation: Reservation,
+    workspace: Workspace,
+    mode: ProcessingMode,
+    options: ProcessingOptions,
+    limits: ArchiveLimits,
+) {
+    let _in_flight = InFlightJob::start();
+    let job_id = workspace.job_id;
+
+    let task_store = store.clone();
+    let result = reservation
+        .run(move || execute_job(&task_store, &workspace, mode, &options, limits))
+        .await;
+
+    match result {
+        Ok(Ok(summary)) => store.complete(&job_id, summary),
+        Ok(Err(e)) => store.fail(&job_id, e),
+        Err(e) => store.fail(&job_id, e.into()),
+    }
+}
+
+/// The blocking part of a job, run on a pool thread. Only the output is
+/// kept once it is over, whether the job succeeded or not.
+fn execute_job(
+    store: &JobStore,
+    workspace: &Workspace,
+    mode: ProcessingMode,
+    options: &ProcessingOptions,
+    limits: ArchiveLimits,
+) -> Result<ProcessingSummary, AppError> {
+    let job_id = workspace.job_id;
+    store.update(&job_id, |status| status.state = JobState::Extracting);
+    let processed = file_processing::validate_and_extract_archive(workspace, limits).and_then(|report| {
+        store.extracted(&job_id, report);
+        mode.run(workspace, options)
+    });
+    remove_inputs(workspace);
+    processed
+}
+
+/// Deletes what a finished job no longer needs, see [`Workspace::remove_inputs`]
+pub(crate) fn remove_inputs(workspace: &Workspace) {
+    if let Err(e) = workspace.remove_inputs() {
+        tracing::warn!("Failed to remove inputs of job {}: {}", workspace.job_id, e);
+    }
+}
@@ -1,8 +1,14 @@
 mod config;
+mod error;
 mod handlers;
+mod jobs;
+mod metrics;
 mod types;
 mod utils;
+mod workspace;
 
+use actix_web::dev::Service;
+use actix_web::middleware::Compress;
 use actix_web::{web, App, HttpServer};
 use env_logger::{self, Env};
 
@@ -16,12 +22,51 @@ async fn main() -> std::io::Result<()> {
 
     let config_clone = config.clone(); // Create a clone for the bind method
 
+    // Shared by every worker so any of them can answer status requests
+    let job_store = web::Data::new(jobs::JobStore::from_config(&config));
+    actix_web::rt::spawn(jobs::evict_periodically(job_store.get_ref().clone()));
+    // One pool for the whole server, not one per actix worker
+    let blocking_pool = web::Data::new(utils::blocking_pool::BlockingPool::new(
+        config.blocking_pool_size,
+        config.blocking_queue_size,
+    )?);
+
     HttpServer::new(move || {
         App::new()
             // .wrap(TracingLogger::default())
+            .wrap_fn(|req, srv| {
+                let response = srv.call(req);
+                async move {
+                    let response = response.await?;
+                    metrics::record_request(response.request(), response.status());
+                    Ok(response)
+                }
+            })
             .app_data(web::Data::new(config.clone()))
+            .app_data(job_store.clone())
+            .app_data(blocking_pool.clone())
+            // Malformed query strings and paths get the same error body as everything else
+            .app_data(web::QueryConfig::default().error_handler(|err, _| {
+                error::AppError::InvalidRequest(err.to_string()).into()
+            }))
+            .app_data(web::PathConfig::default().error_handler(|err, _| {
+                error::AppError::InvalidRequest(err.to_string()).into()
+            }))
             .service(handlers::upload_zip)
             .service(handlers::upload_large_zip)
+            .service(handlers::submit_upload_job)
+            .service(handlers::submit_upload_large_job)
+            .service(handlers::job_status)
+            .service(handlers::job_result)
+            // Artifacts are gzip/br encoded when the client accepts it
+            .service(
+                web::scope("/results")
+                    .wrap(Compress::default())
+                    .service(handlers::download_result),
+            )
+            .service(handlers::liveness)
+            .service(handlers::readiness)
+            .service(handlers::export_metrics)
     })
     .bind((config_clone.server_host.as_str(), config_clone.server_port))?
     .run()
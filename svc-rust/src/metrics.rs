@@ -0,0 +1,140 @@
+use std::sync::LazyLock;
+
+use actix_web::http::StatusCode;
+use actix_web::HttpRequest;
+
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+
+/// Buckets for extraction and per-file parsing, from a few milliseconds up to
+/// the multi-minute runs of the largest benchmark archives
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+/// Process-wide metrics exposed on `/metrics`.
+///
+/// Kept in a static rather than in `web::Data` because most of them are
+/// recorded deep inside the blocking extraction and parsing code, which has no
+/// access to application state.
+pub struct Metrics {
+    registry: Registry,
+    pub upload_bytes: IntCounter,
+    pub http_requests: IntCounterVec,
+    pub extraction_duration: Histogram,
+    pub parse_duration: HistogramVec,
+    pub records_parsed: IntCounterVec,
+    pub actors_emitted: IntCounterVec,
+    pub parse_errors_skipped: IntCounter,
+    pub records_filtered: IntCounter,
+    pub jobs_in_flight: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("svc_rust".to_string()), None)?;
+
+        let upload_bytes = IntCounter::new("upload_bytes_total", "Bytes received in upload bodies")?;
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by endpoint and status"),
+            &["endpoint", "method", "status"],
+        )?;
+        let extraction_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "extraction_duration_seconds",
+                "Time spent extracting an uploaded archive",
+            )
+            .buckets(DURATION_BUCKETS.to_vec()),
+        )?;
+        let parse_duration = HistogramVec::new(
+            HistogramOpts::new("parse_duration_seconds", "Time spent parsing one JSON file")
+                .buckets(DURATION_BUCKETS.to_vec()),
+            &["mode"],
+        )?;
+        let records_parsed = IntCounterVec::new(
+            Opts::new("records_parsed_total", "Event records parsed from JSON files"),
+            &["mode"],
+        )?;
+        let actors_emitted = IntCounterVec::new(
+            Opts::new("actors_emitted_total", "Actors written to output files"),
+            &["mode"],
+        )?;
+        let parse_errors_skipped = IntCounter::new(
+            "parse_errors_skipped_total",
+            "Records left out of processing because they failed to parse",
+        )?;
+        let records_filtered = IntCounter::new(
+            "records_filtered_total",
+            "Parsed records dropped because they did not match the request filter",
+        )?;
+        let jobs_in_flight = IntGauge::new(
+            "jobs_in_flight",
+            "Uploads currently being extracted or processed",
+        )?;
+
+        registry.register(Box::new(upload_bytes.clone()))?;
+        registry.register(Box::new(http_requests.clone()))?;
+        registry.register(Box::new(extraction_duration.clone()))?;
+        registry.register(Box::new(parse_duration.clone()))?;
+        registry.register(Box::new(records_parsed.clone()))?;
+        registry.register(Box::new(actors_emitted.clone()))?;
+        registry.register(Box::new(parse_errors_skipped.clone()))?;
+        registry.register(Box::new(records_filtered.clone()))?;
+        registry.register(Box::new(jobs_in_flight.clone()))?;
+
+        Ok(Self {
+            registry,
+            upload_bytes,
+            http_requests,
+            extraction_duration,
+            parse_duration,
+            records_parsed,
+            actors_emitted,
+            parse_errors_skipped,
+            records_filtered,
+            jobs_in_flight,
+        })
+    }
+
+    /// Renders every metric in the Prometheus text format
+    pub fn render(&self) -> Result<Vec<u8>, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+pub static METRICS: LazyLock<Metrics> =
+    LazyLock::new(|| Metrics::new().expect("metric definitions are valid"));
+
+/// Counts an upload in `jobs_in_flight` for as long as it is alive
+pub struct InFlightJob {
+    _private: (),
+}
+
+impl InFlightJob {
+    pub fn start() -> Self {
+        METRICS.jobs_in_flight.inc();
+        Self { _private: () }
+    }
+}
+
+impl Drop for InFlightJob {
+    fn drop(&mut self) {
+        METRICS.jobs_in_flight.dec();
+    }
+}
+
+/// Counts a finished request under its route pattern, so job IDs in the path
+/// do not create a series per job
+pub fn record_request(request: &HttpRequest, status: StatusCode) {
+    let endpoint = request
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+    METRICS
+        .http_requests
+        .with_label_values(&[&endpoint, request.method().as_str(), status.as_str()])
+        .inc();
+}
@@ -1,4 +1,9 @@
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use serde_json::value::RawValue;
+
+use crate::utils::tabular::{Column, ColumnType};
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -8,9 +13,47 @@ pub struct Event {
     pub type_field: Option<String>,
     pub actor: Option<Actor>,
     pub repo: Option<Repo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub org: Option<Org>,
+    /// Kept as it was read, see [`Event::payload`]
+    pub payload: Option<Box<RawValue>>,
     pub public: bool,
-    #[serde(rename = "created_at")]
-    pub created_at: Option<String>,
+    /// When the event happened, see [`deserialize_created_at`]
+    #[serde(rename = "created_at", default, deserialize_with = "deserialize_created_at")]
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+/// Formats of timestamps without an offset, which are taken as UTC
+const NAIVE_TIME_FORMATS: [&str; 2] = ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"];
+
+/// Reads `created_at` from an RFC 3339 timestamp, or from one without an
+/// offset as UTC. Anything else leaves it unset rather than failing the
+/// event, since it is only needed by the time series and `created_at`
+/// filters.
+fn deserialize_created_at<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(Value::String(text)) = Option::<Value>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    if let Ok(time) = DateTime::parse_from_rfc3339(&text) {
+        return Ok(Some(time.with_timezone(&Utc)));
+    }
+    Ok(NAIVE_TIME_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(&text, format).ok())
+        .map(|time| time.and_utc()))
+}
+
+impl Event {
+    /// Decodes the payload according to the event's `type`. Payloads are
+    /// only decoded when asked for, as most extractors never look at them.
+    pub fn payload(&self) -> Option<Payload> {
+        self.payload
+            .as_deref()
+            .map(|payload| Payload::from_raw(self.type_field.as_deref(), payload))
+    }
 }
 
 #[derive(Default, Debug, Deserialize, Serialize, Clone)]
@@ -25,9 +68,447 @@ pub struct Actor {
     pub avatar_url: Option<String>,
 }
 
+impl Actor {
+    /// Columns of an actor in CSV and Parquet output
+    pub fn columns() -> Vec<Column> {
+        vec![
+            Column::new("id", ColumnType::Int),
+            Column::new("login", ColumnType::String),
+            Column::new("display_login", ColumnType::String),
+            Column::new("gravatar_id", ColumnType::String),
+            Column::new("url", ColumnType::String),
+            Column::new("avatar_url", ColumnType::String),
+        ]
+    }
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct Org {
+    pub id: Option<i64>,
+    pub login: Option<String>,
+    pub gravatar_id: Option<String>,
+    pub url: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+impl Org {
+    /// Columns of an organization in CSV and Parquet output
+    pub fn columns() -> Vec<Column> {
+        vec![
+            Column::new("id", ColumnType::Int),
+            Column::new("login", ColumnType::String),
+            Column::new("gravatar_id", ColumnType::String),
+            Column::new("url", ColumnType::String),
+            Column::new("avatar_url", ColumnType::String),
+        ]
+    }
+}
+
 #[derive(Default, Debug, Deserialize, Serialize, Clone)]
 pub struct Repo {
     pub id: Option<u64>,
     pub name: Option<String>,
     pub url: Option<String>,
 }
+
+impl Repo {
+    /// Columns of a repository in CSV and Parquet output
+    pub fn columns() -> Vec<Column> {
+        vec![
+            Column::new("id", ColumnType::UInt),
+            Column::new("name", ColumnType::String),
+            Column::new("url", ColumnType::String),
+        ]
+    }
+}
+
+/// Type-specific part of an event, selected by the event's `type`.
+///
+/// Event types without a model here, and payloads that do not match their
+/// model, are kept as [`Payload::Unknown`] so a single odd record never fails
+/// the whole file. Payloads embedding issues, pull requests or full
+/// repositories are boxed to keep the enum small.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Payload {
+    Push(PushEventPayload),
+    PullRequest(Box<PullRequestEventPayload>),
+    Create(CreateEventPayload),
+    Delete(DeleteEventPayload),
+    Issues(Box<IssuesEventPayload>),
+    IssueComment(Box<IssueCommentEventPayload>),
+    Watch(WatchEventPayload),
+    Fork(Box<ForkEventPayload>),
+    Member(MemberEventPayload),
+    Release(Box<ReleaseEventPayload>),
+    Gollum(GollumEventPayload),
+    Unknown(serde_json::Value),
+}
+
+impl Payload {
+    fn from_raw(event_type: Option<&str>, raw: &RawValue) -> Self {
+        fn typed<T: for<'de> Deserialize<'de>>(raw: &RawValue, wrap: fn(T) -> Payload) -> Option<Payload> {
+            serde_json::from_str(raw.get()).ok().map(wrap)
+        }
+
+        let payload = match event_type {
+            Some("PushEvent") => typed(raw, Self::Push),
+            Some("PullRequestEvent") => typed(raw, |payload| Self::PullRequest(Box::new(payload))),
+            Some("CreateEvent") => typed(raw, Self::Create),
+            Some("DeleteEvent") => typed(raw, Self::Delete),
+            Some("IssuesEvent") => typed(raw, |payload| Self::Issues(Box::new(payload))),
+            Some("IssueCommentEvent") => typed(raw, |payload| Self::IssueComment(Box::new(payload))),
+            Some("WatchEvent") => typed(raw, Self::Watch),
+            Some("ForkEvent") => typed(raw, |payload| Self::Fork(Box::new(payload))),
+            Some("MemberEvent") => typed(raw, Self::Member),
+            Some("ReleaseEvent") => typed(raw, |payload| Self::Release(Box::new(payload))),
+            Some("GollumEvent") => typed(raw, Self::Gollum),
+            _ => None,
+        };
+
+        payload.unwrap_or_else(|| {
+            Self::Unknown(serde_json::from_str(raw.get()).unwrap_or(serde_json::Value::Null))
+        })
+    }
+
+    /// What happened, e.g. `opened` or `started`, for payloads that say
+    pub fn action(self) -> Option<String> {
+        match self {
+            Self::PullRequest(payload) => payload.action,
+            Self::Issues(payload) => payload.action,
+            Self::IssueComment(payload) => payload.action,
+            Self::Watch(payload) => payload.action,
+            Self::Member(payload) => payload.action,
+            Self::Release(payload) => payload.action,
+            Self::Unknown(mut payload) => match payload.get_mut("action")?.take() {
+                serde_json::Value::String(action) => Some(action),
+                _ => None,
+            },
+            Self::Push(_) | Self::Create(_) | Self::Delete(_) | Self::Fork(_) | Self::Gollum(_) => None,
+        }
+    }
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct User {
+    pub id: Option<i64>,
+    pub login: Option<String>,
+    pub avatar_url: Option<String>,
+    pub gravatar_id: Option<String>,
+    pub url: Option<String>,
+    pub html_url: Option<String>,
+    #[serde(rename = "type")]
+    pub type_field: Option<String>,
+    pub site_admin: Option<bool>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct Label {
+    pub url: Option<String>,
+    pub name: Option<String>,
+    pub color: Option<String>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct CommitAuthor {
+    pub email: Option<String>,
+    pub name: Option<String>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct Commit {
+    pub sha: Option<String>,
+    pub author: Option<CommitAuthor>,
+    pub message: Option<String>,
+    pub distinct: Option<bool>,
+    pub url: Option<String>,
+}
+
+/// Full repository object, as embedded in fork and pull request payloads
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct RepoDetails {
+    pub id: Option<u64>,
+    pub name: Option<String>,
+    pub full_name: Option<String>,
+    pub owner: Option<User>,
+    pub private: Option<bool>,
+    pub html_url: Option<String>,
+    pub description: Option<String>,
+    pub fork: Option<bool>,
+    pub url: Option<String>,
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub pushed_at: Option<String>,
+    pub homepage: Option<String>,
+    pub size: Option<u64>,
+    pub stargazers_count: Option<u64>,
+    pub watchers_count: Option<u64>,
+    pub language: Option<String>,
+    pub forks_count: Option<u64>,
+    pub open_issues_count: Option<u64>,
+    pub default_branch: Option<String>,
+    pub public: Option<bool>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct Issue {
+    pub id: Option<u64>,
+    pub url: Option<String>,
+    pub html_url: Option<String>,
+    pub number: Option<u64>,
+    pub title: Option<String>,
+    pub user: Option<User>,
+    #[serde(default)]
+    pub labels: Vec<Label>,
+    pub state: Option<String>,
+    pub locked: Option<bool>,
+    pub assignee: Option<User>,
+    pub comments: Option<u64>,
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub closed_at: Option<String>,
+    pub body: Option<String>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct Comment {
+    pub id: Option<u64>,
+    pub url: Option<String>,
+    pub html_url: Option<String>,
+    pub issue_url: Option<String>,
+    pub user: Option<User>,
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub body: Option<String>,
+}
+
+/// Head or base of a pull request
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct PullRequestRef {
+    pub label: Option<String>,
+    #[serde(rename = "ref")]
+    pub ref_field: Option<String>,
+    pub sha: Option<String>,
+    pub user: Option<User>,
+    pub repo: Option<RepoDetails>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct PullRequest {
+    pub id: Option<u64>,
+    pub url: Option<String>,
+    pub html_url: Option<String>,
+    pub number: Option<u64>,
+    pub state: Option<String>,
+    pub locked: Option<bool>,
+    pub title: Option<String>,
+    pub user: Option<User>,
+    pub body: Option<String>,
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub closed_at: Option<String>,
+    pub merged_at: Option<String>,
+    pub merge_commit_sha: Option<String>,
+    pub assignee: Option<User>,
+    pub head: Option<PullRequestRef>,
+    pub base: Option<PullRequestRef>,
+    pub merged: Option<bool>,
+    pub mergeable: Option<bool>,
+    pub mergeable_state: Option<String>,
+    pub merged_by: Option<User>,
+    pub comments: Option<u64>,
+    pub review_comments: Option<u64>,
+    pub commits: Option<u64>,
+    pub additions: Option<u64>,
+    pub deletions: Option<u64>,
+    pub changed_files: Option<u64>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct Page {
+    pub page_name: Option<String>,
+    pub title: Option<String>,
+    pub summary: Option<String>,
+    pub action: Option<String>,
+    pub sha: Option<String>,
+    pub html_url: Option<String>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct Release {
+    pub id: Option<u64>,
+    pub url: Option<String>,
+    pub html_url: Option<String>,
+    pub tag_name: Option<String>,
+    pub target_commitish: Option<String>,
+    pub name: Option<String>,
+    pub draft: Option<bool>,
+    pub author: Option<User>,
+    pub prerelease: Option<bool>,
+    pub created_at: Option<String>,
+    pub published_at: Option<String>,
+    #[serde(default)]
+    pub assets: Vec<serde_json::Value>,
+    pub tarball_url: Option<String>,
+    pub zipball_url: Option<String>,
+    pub body: Option<String>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct PushEventPayload {
+    pub push_id: Option<u64>,
+    pub size: Option<u64>,
+    pub distinct_size: Option<u64>,
+    #[serde(rename = "ref")]
+    pub ref_field: Option<String>,
+    pub head: Option<String>,
+    pub before: Option<String>,
+    #[serde(default)]
+    pub commits: Vec<Commit>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct PullRequestEventPayload {
+    pub action: Option<String>,
+    pub number: Option<u64>,
+    pub pull_request: Option<PullRequest>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct CreateEventPayload {
+    #[serde(rename = "ref")]
+    pub ref_field: Option<String>,
+    pub ref_type: Option<String>,
+    pub master_branch: Option<String>,
+    pub description: Option<String>,
+    pub pusher_type: Option<String>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct DeleteEventPayload {
+    #[serde(rename = "ref")]
+    pub ref_field: Option<String>,
+    pub ref_type: Option<String>,
+    pub pusher_type: Option<String>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct IssuesEventPayload {
+    pub action: Option<String>,
+    pub issue: Option<Issue>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct IssueCommentEventPayload {
+    pub action: Option<String>,
+    pub issue: Option<Issue>,
+    pub comment: Option<Comment>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct WatchEventPayload {
+    pub action: Option<String>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct ForkEventPayload {
+    pub forkee: Option<RepoDetails>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct MemberEventPayload {
+    pub member: Option<User>,
+    pub action: Option<String>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct ReleaseEventPayload {
+    pub action: Option<String>,
+    pub release: Option<Release>,
+}
+
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct GollumEventPayload {
+    #[serde(default)]
+    pub pages: Vec<Page>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(json: &str) -> Event {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn created_at_is_read_leniently() {
+        let created_at = |value: &str| event(&format!(r#"{{"public":true,"created_at":{}}}"#, value)).created_at;
+        let utc = |time: &str| Some(time.parse::<DateTime<Utc>>().unwrap());
+
+        assert_eq!(created_at(r#""2003-07-22T06:51:06Z""#), utc("2003-07-22T06:51:06Z"));
+        assert_eq!(created_at(r#""2003-07-22T08:51:06+02:00""#), utc("2003-07-22T06:51:06Z"));
+        assert_eq!(created_at(r#""2003-07-22T06:51:06""#), utc("2003-07-22T06:51:06Z"));
+        assert_eq!(created_at(r#""2003-07-22 06:51:06.250""#), utc("2003-07-22T06:51:06.250Z"));
+        for invalid in [r#""yesterday""#, r#""2003-07-22""#, "1058856666", "null", "{}"] {
+            assert_eq!(created_at(invalid), None, "{}", invalid);
+        }
+        assert_eq!(event(r#"{"public":true}"#).created_at, None);
+    }
+
+    #[test]
+    fn payload_is_decoded_by_event_type() {
+        let event = event(
+            r#"{"type":"PushEvent","public":true,"payload":{"push_id":7,"size":1,"commits":[{"sha":"abc"}]}}"#,
+        );
+        let Some(Payload::Push(push)) = event.payload() else {
+            panic!("expected a push payload, got {:?}", event.payload());
+        };
+        assert_eq!(push.push_id, Some(7));
+        assert_eq!(push.commits[0].sha.as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn boxed_payloads_are_decoded() {
+        let event = event(r#"{"type":"ForkEvent","public":true,"payload":{"forkee":{"id":3,"name":"fork"}}}"#);
+        let Some(Payload::Fork(fork)) = event.payload() else {
+            panic!("expected a fork payload, got {:?}", event.payload());
+        };
+        assert_eq!(fork.forkee.and_then(|forkee| forkee.id), Some(3));
+    }
+
+    #[test]
+    fn unknown_types_and_mismatched_payloads_fall_back_to_unknown() {
+        let unknown = event(r#"{"type":"SponsorshipEvent","public":true,"payload":{"tier":"gold"}}"#);
+        assert!(matches!(unknown.payload(), Some(Payload::Unknown(value)) if value["tier"] == "gold"));
+
+        let mismatched = event(r#"{"type":"PushEvent","public":true,"payload":{"size":"large"}}"#);
+        assert!(matches!(mismatched.payload(), Some(Payload::Unknown(value)) if value["size"] == "large"));
+    }
+
+    #[test]
+    fn payload_is_written_back_as_read() {
+        let raw = r#"{"type":"WatchEvent","public":false,"payload":{"action":"started","extra":[1,2]}}"#;
+        let event = event(raw);
+        assert!(event.payload().is_some());
+        let written = serde_json::to_value(&event).unwrap();
+        assert_eq!(written["payload"], serde_json::json!({"action": "started", "extra": [1, 2]}));
+    }
+
+    #[test]
+    fn action_is_read_from_typed_and_unknown_payloads() {
+        let watch = event(r#"{"type":"WatchEvent","public":true,"payload":{"action":"started"}}"#);
+        assert_eq!(watch.payload().and_then(Payload::action).as_deref(), Some("started"));
+
+        let unknown = event(r#"{"type":"DiscussionEvent","public":true,"payload":{"action":"created"}}"#);
+        assert_eq!(unknown.payload().and_then(Payload::action).as_deref(), Some("created"));
+
+        let push = event(r#"{"type":"PushEvent","public":true,"payload":{"action":"ignored"}}"#);
+        assert_eq!(push.payload().and_then(Payload::action), None);
+    }
+
+    #[test]
+    fn events_without_payload_have_none() {
+        assert!(event(r#"{"type":"PushEvent","public":true}"#).payload().is_none());
+    }
+}
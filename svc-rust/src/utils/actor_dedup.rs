@@ -0,0 +1,490 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Lines, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::error::AppError;
+use crate::types::{Actor, Event};
+use crate::utils::extractors::Extractor;
+use crate::utils::output::OutputSink;
+use crate::utils::tabular::{Column, ColumnType};
+
+/// Identity actors are merged on: the numeric id when there is one, the login
+/// otherwise
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActorKey {
+    Id(i64),
+    Login(String),
+}
+
+impl ActorKey {
+    fn of(actor: &Actor) -> Option<Self> {
+        match (actor.id, &actor.login) {
+            (Some(id), _) => Some(Self::Id(id)),
+            (None, Some(login)) => Some(Self::Login(login.clone())),
+            (None, None) => None,
+        }
+    }
+}
+
+/// State kept per actor by an [`ActorAggregator`]. It is serialized as is
+/// when spilled to disk, and turned into its output form once complete.
+pub trait ActorAggregate: Serialize + DeserializeOwned + Send {
+    type Output: Serialize;
+
+    fn new(actor: &Actor) -> Self;
+
+    /// Accounts for one event of this actor
+    fn record(&mut self, event: &Event);
+
+    /// Folds in the state of the same actor gathered elsewhere
+    fn merge(&mut self, other: Self);
+
+    fn into_output(self) -> Self::Output;
+
+    /// Columns of [`Self::Output`] in CSV and Parquet output
+    fn columns() -> Vec<Column>;
+}
+
+/// One deduplicated actor with when and how often it was seen
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActorStats {
+    #[serde(flatten)]
+    pub actor: Actor,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub event_count: u64,
+}
+
+impl ActorStats {
+    /// Widens the seen range
+    fn see(&mut self, first: DateTime<Utc>, last: DateTime<Utc>) {
+        if self.first_seen.is_none_or(|seen| first < seen) {
+            self.first_seen = Some(first);
+        }
+        if self.last_seen.is_none_or(|seen| last > seen) {
+            self.last_seen = Some(last);
+        }
+    }
+}
+
+impl ActorAggregate for ActorStats {
+    type Output = Self;
+
+    fn new(actor: &Actor) -> Self {
+        Self {
+            actor: actor.clone(),
+            first_seen: None,
+            last_seen: None,
+            event_count: 0,
+        }
+    }
+
+    fn record(&mut self, event: &Event) {
+        self.event_count += 1;
+        if let Some(created_at) = event.created_at {
+            self.see(created_at, created_at);
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.event_count += other.event_count;
+        if let Some(first) = other.first_seen {
+            self.see(first, other.last_seen.unwrap_or(first));
+        }
+    }
+
+    fn into_output(self) -> Self {
+        self
+    }
+
+    fn columns() -> Vec<Column> {
+        let mut columns = Actor::columns();
+        columns.extend([
+            Column::new("first_seen", ColumnType::String),
+            Column::new("last_seen", ColumnType::String),
+            Column::new("event_count", ColumnType::Int),
+        ]);
+        columns
+    }
+}
+
+/// What one actor did: events per type, repositories touched and how many
+/// events were public
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActorActivity {
+    actor: Actor,
+    events_by_type: BTreeMap<String, u64>,
+    /// Repository ids, or names for repositories without one
+    repos: BTreeSet<String>,
+    public_events: u64,
+    private_events: u64,
+}
+
+/// [`ActorActivity`] as written to the output, with repositories reduced to
+/// a count
+#[derive(Debug, Serialize)]
+pub struct ActorActivityReport {
+    #[serde(flatten)]
+    pub actor: Actor,
+    pub events_by_type: BTreeMap<String, u64>,
+    pub distinct_repos: usize,
+    pub public_events: u64,
+    pub private_events: u64,
+    /// Share of the actor's events that were public, from 0 to 1
+    pub public_ratio: f64,
+}
+
+impl ActorAggregate for ActorActivity {
+    type Output = ActorActivityReport;
+
+    fn new(actor: &Actor) -> Self {
+        Self {
+            actor: actor.clone(),
+            events_by_type: BTreeMap::new(),
+            repos: BTreeSet::new(),
+            public_events: 0,
+            private_events: 0,
+        }
+    }
+
+    fn record(&mut self, event: &Event) {
+        let event_type = event.type_field.as_deref().unwrap_or("unknown");
+        *self.events_by_type.entry(event_type.to_string()).or_default() += 1;
+
+        if let Some(repo) = &event.repo {
+            let key = match (&repo.id, &repo.name) {
+                (Some(id), _) => Some(id.to_string()),
+                (None, Some(name)) => Some(name.clone()),
+                (None, None) => None,
+            };
+            self.repos.extend(key);
+        }
+
+        if event.public {
+            self.public_events += 1;
+        } else {
+            self.private_events += 1;
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        for (event_type, count) in other.events_by_type {
+            *self.events_by_type.entry(event_type).or_default() += count;
+        }
+        self.repos.extend(other.repos);
+        self.public_events += other.public_events;
+        self.private_events += other.private_events;
+    }
+
+    fn into_output(self) -> ActorActivityReport {
+        let total = self.public_events + self.private_events;
+        let public_ratio = if total == 0 {
+            0.0
+        } else {
+            self.public_events as f64 / total as f64
+        };
+        ActorActivityReport {
+            actor: self.actor,
+            events_by_type: self.events_by_type,
+            distinct_repos: self.repos.len(),
+            public_events: self.public_events,
+            private_events: self.private_events,
+            public_ratio,
+        }
+    }
+
+    fn columns() -> Vec<Column> {
+        let mut columns = Actor::columns();
+        columns.extend([
+            Column::new("events_by_type", ColumnType::Json),
+            Column::new("distinct_repos", ColumnType::Int),
+            Column::new("public_events", ColumnType::Int),
+            Column::new("private_events", ColumnType::Int),
+            Column::new("public_ratio", ColumnType::Float),
+        ]);
+        columns
+    }
+}
+
+/// Spilled entry, one per line of a run file
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "A: ActorAggregate")]
+struct SpilledActor<A> {
+    key: ActorKey,
+    state: A,
+}
+
+/// Emits each actor once, keyed by [`ActorKey`], with the state `A` gathered
+/// from all of its events: [`ActorStats`] for `unique_actors` and
+/// [`ActorActivity`] for `actor_activity`.
+///
+/// At most `max_in_memory` actors are held at a time. Past that, the current
+/// batch is written to a sorted run file in `scratch_dir` and the runs are
+/// merged when the job finishes, so the output is ordered by key and memory
+/// stays bounded however many distinct actors an upload has.
+pub struct ActorAggregator<A> {
+    name: &'static str,
+    actors: BTreeMap<ActorKey, A>,
+    max_in_memory: usize,
+    scratch_dir: PathBuf,
+    runs: Vec<PathBuf>,
+}
+
+impl<A: ActorAggregate> ActorAggregator<A> {
+    pub fn new(name: &'static str, max_in_memory: usize, scratch_dir: &Path) -> Self {
+        Self {
+            name,
+            actors: BTreeMap::new(),
+            max_in_memory: max_in_memory.max(1),
+            scratch_dir: scratch_dir.to_path_buf(),
+            runs: Vec::new(),
+        }
+    }
+
+    /// Writes the in-memory actors, already sorted by key, to a new run file
+    fn spill(&mut self) -> Result<(), AppError> {
+        std::fs::create_dir_all(&self.scratch_dir)?;
+        let path = self
+            .scratch_dir
+            .join(format!("{}-{}.ndjson", self.name, self.runs.len()));
+        let mut writer = BufWriter::new(File::create(&path)?);
+        for (key, state) in std::mem::take(&mut self.actors) {
+            serde_json::to_writer(&mut writer, &SpilledActor { key, state })
+                .map_err(|e| AppError::Internal(format!("Failed to spill actors: {}", e)))?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        tracing::debug!("Spilled actors to {}", path.display());
+        self.runs.push(path);
+        Ok(())
+    }
+
+    /// K-way merge of the run files, combining entries with the same key
+    fn merge_runs(&mut self, out: &mut OutputSink) -> Result<(), AppError> {
+        let mut runs = self
+            .runs
+            .iter()
+            .map(|path| Ok(RunReader::new(File::open(path)?)))
+            .collect::<Result<Vec<_>, AppError>>()?;
+
+        let mut heads = BinaryHeap::new();
+        for (index, run) in runs.iter_mut().enumerate() {
+            if let Some(entry) = run.next_entry()? {
+                heads.push(Reverse(HeapEntry { key: entry.key, index, state: entry.state }));
+            }
+        }
+
+        let mut current: Option<(ActorKey, A)> = None;
+        while let Some(Reverse(HeapEntry { key, index, state })) = heads.pop() {
+            if let Some(entry) = runs[index].next_entry()? {
+                heads.push(Reverse(HeapEntry { key: entry.key, index, state: entry.state }));
+            }
+            match &mut current {
+                Some((current_key, current_state)) if *current_key == key => current_state.merge(state),
+                _ => {
+                    if let Some((_, done)) = current.replace((key, state)) {
+                        out.push(&done.into_output())?;
+                    }
+                }
+            }
+        }
+        if let Some((_, done)) = current {
+            out.push(&done.into_output())?;
+        }
+
+        for path in self.runs.drain(..) {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl<A: ActorAggregate> Extractor for ActorAggregator<A> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn extract(&mut self, event: &Event, _out: &mut OutputSink) -> Result<(), AppError> {
+        let Some(actor) = &event.actor else {
+            return Ok(());
+        };
+        let Some(key) = ActorKey::of(actor) else {
+            return Ok(());
+        };
+
+        if !self.actors.contains_key(&key) && self.actors.len() >= self.max_in_memory {
+            self.spill()?;
+        }
+        self.actors
+            .entry(key)
+            .or_insert_with(|| A::new(actor))
+            .record(event);
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut OutputSink) -> Result<(), AppError> {
+        if self.runs.is_empty() {
+            for state in std::mem::take(&mut self.actors).into_values() {
+                out.push(&state.into_output())?;
+            }
+            return Ok(());
+        }
+
+        if !self.actors.is_empty() {
+            self.spill()?;
+        }
+        self.merge_runs(out)
+    }
+}
+
+/// Head of one run during the merge, ordered by key and then by run so equal
+/// keys come out next to each other
+struct HeapEntry<A> {
+    key: ActorKey,
+    index: usize,
+    state: A,
+}
+
+impl<A> PartialEq for HeapEntry<A> {
+    fn eq(&self, other: &Self) -> bool {
+        (&self.key, self.index) == (&other.key, other.index)
+    }
+}
+
+impl<A> Eq for HeapEntry<A> {}
+
+impl<A> PartialOrd for HeapEntry<A> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<A> Ord for HeapEntry<A> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (&self.key, self.index).cmp(&(&other.key, other.index))
+    }
+}
+
+struct RunReader {
+    lines: Lines<BufReader<File>>,
+}
+
+impl RunReader {
+    fn new(file: File) -> Self {
+        Self {
+            lines: BufReader::new(file).lines(),
+        }
+    }
+
+    fn next_entry<A: ActorAggregate>(&mut self) -> Result<Option<SpilledActor<A>>, AppError> {
+        match self.lines.next() {
+            Some(line) => serde_json::from_str(&line?)
+                .map(Some)
+                .map_err(|e| AppError::Internal(format!("Failed to read spilled actors: {}", e))),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::output::OutputFormat;
+    use serde_json::{json, Value};
+
+    fn event(actor: Value, event_type: &str, repo: u64, created_at: &str) -> Event {
+        let event = json!({
+            "type": event_type,
+            "actor": actor,
+            "repo": {"id": repo},
+            "public": repo.is_multiple_of(2),
+            "created_at": created_at,
+        });
+        serde_json::from_str(&event.to_string()).unwrap()
+    }
+
+    fn events() -> Vec<Event> {
+        vec![
+            event(json!({"id": 2, "login": "two"}), "PushEvent", 10, "2024-01-02T00:00:00Z"),
+            event(json!({"id": 1, "login": "one"}), "PushEvent", 10, "2024-01-03T00:00:00Z"),
+            event(json!({"login": "anonymous"}), "WatchEvent", 11, "2024-01-01T00:00:00Z"),
+            event(json!({"id": 3, "login": "three"}), "IssuesEvent", 12, "2024-01-05T00:00:00Z"),
+            event(json!({"id": 1, "login": "one"}), "WatchEvent", 11, "2024-01-01T00:00:00Z"),
+            event(json!({"id": 2, "login": "two"}), "PushEvent", 12, "2024-01-04T00:00:00Z"),
+            event(json!({"id": 1, "login": "one"}), "PushEvent", 10, "2024-01-02T00:00:00Z"),
+            event(json!({}), "PushEvent", 10, "2024-01-02T00:00:00Z"),
+        ]
+    }
+
+    /// Runs every event through an aggregator holding at most
+    /// `max_in_memory` actors and returns the records it wrote
+    fn aggregate<A: ActorAggregate>(max_in_memory: usize) -> (Vec<Value>, usize) {
+        let dir = tempfile::tempdir().unwrap();
+        let scratch = dir.path().join("scratch");
+        let path = dir.path().join("out.ndjson");
+        let mut out = OutputSink::create(&path, OutputFormat::Ndjson, A::columns()).unwrap();
+        let mut aggregator = ActorAggregator::<A>::new("actors", max_in_memory, &scratch);
+        for event in events() {
+            aggregator.extract(&event, &mut out).unwrap();
+        }
+        let spilled = aggregator.runs.len();
+        aggregator.finish(&mut out).unwrap();
+        out.close().unwrap();
+
+        assert!(aggregator.runs.is_empty());
+        if scratch.exists() {
+            assert_eq!(std::fs::read_dir(&scratch).unwrap().count(), 0, "run files are removed");
+        }
+        let records = std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        (records, spilled)
+    }
+
+    #[test]
+    fn actors_are_merged_by_id_then_login() {
+        let (records, spilled) = aggregate::<ActorStats>(100);
+        assert_eq!(spilled, 0);
+
+        let keys: Vec<_> = records.iter().map(|record| record["login"].clone()).collect();
+        assert_eq!(keys, [json!("one"), json!("two"), json!("three"), json!("anonymous")]);
+
+        assert_eq!(records[0]["event_count"], 3);
+        assert_eq!(records[0]["first_seen"], "2024-01-01T00:00:00Z");
+        assert_eq!(records[0]["last_seen"], "2024-01-03T00:00:00Z");
+        assert_eq!(records[1]["event_count"], 2);
+        assert_eq!(records[3]["event_count"], 1);
+    }
+
+    #[test]
+    fn spilled_runs_merge_to_the_same_output() {
+        let (in_memory, _) = aggregate::<ActorStats>(100);
+        let (merged, spilled) = aggregate::<ActorStats>(1);
+        assert!(spilled > 1);
+        assert_eq!(merged, in_memory);
+    }
+
+    #[test]
+    fn activity_is_merged_across_runs() {
+        let (in_memory, _) = aggregate::<ActorActivity>(100);
+        let (merged, spilled) = aggregate::<ActorActivity>(2);
+        assert!(spilled > 0);
+        assert_eq!(merged, in_memory);
+
+        let one = &merged[0];
+        assert_eq!(one["login"], "one");
+        assert_eq!(one["events_by_type"], json!({"PushEvent": 2, "WatchEvent": 1}));
+        assert_eq!(one["distinct_repos"], 2);
+        assert_eq!(one["public_events"], 2);
+        assert_eq!(one["private_events"], 1);
+    }
+}
@@ -0,0 +1,235 @@
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use futures::channel::oneshot;
+
+type Task = Box<dyn FnOnce() + Send + 'static>;
+
+/// Errors returned when handing work to the [`BlockingPool`]
+#[derive(Debug)]
+pub enum PoolError {
+    /// The queue already holds as many tasks as it was configured for
+    Saturated,
+    /// The task panicked or the pool shut down before it completed
+    Canceled,
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Saturated => write!(f, "Processing queue is full, try again later"),
+            Self::Canceled => write!(f, "Processing task did not complete"),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+/// Fixed set of OS threads for blocking work (ZIP extraction, JSON parsing).
+///
+/// Keeping this work off the actix workers means a large archive only ever
+/// occupies one of these threads, while health checks and other uploads are
+/// still served. Tasks wait in a bounded queue; once it is full new work is
+/// rejected with [`PoolError::Saturated`] instead of piling up.
+#[derive(Clone)]
+pub struct BlockingPool {
+    sender: SyncSender<Task>,
+    size: usize,
+    queue_capacity: usize,
+    queued: Arc<AtomicUsize>,
+    active: Arc<AtomicUsize>,
+}
+
+impl BlockingPool {
+    pub fn new(size: usize, queue_capacity: usize) -> Result<Self, std::io::Error> {
+        let size = size.max(1);
+        let (sender, receiver) = mpsc::sync_channel::<Task>(queue_capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let queued = Arc::new(AtomicUsize::new(0));
+        let active = Arc::new(AtomicUsize::new(0));
+
+        for index in 0..size {
+            let receiver = Arc::clone(&receiver);
+            let queued = Arc::clone(&queued);
+            let active = Arc::clone(&active);
+            thread::Builder::new()
+                .name(format!("blocking-worker-{}", index))
+                .spawn(move || worker_loop(receiver, queued, active))?;
+        }
+
+        Ok(Self {
+            sender,
+            size,
+            queue_capacity,
+            queued,
+            active,
+        })
+    }
+
+    /// Runs `f` on one of the pool threads and resolves with its return value
+    pub async fn run<F, T>(&self, f: F) -> Result<T, PoolError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.reserve()?.run(f).await
+    }
+
+    /// Takes a place in the queue for a task to be handed over later, so
+    /// work that has been accepted cannot be turned away once it is ready
+    /// to run. The place is given back if the reservation is dropped unused.
+    pub fn reserve(&self) -> Result<Reservation, PoolError> {
+        self.queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                (queued < self.queue_capacity).then_some(queued + 1)
+            })
+            .map_err(|_| PoolError::Saturated)?;
+        Ok(Reservation {
+            pool: Some(self.clone()),
+        })
+    }
+
+    /// Tasks submitted or reserved but not yet picked up by a thread
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Tasks currently executing
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Whether a new task would currently be rejected
+    pub fn is_saturated(&self) -> bool {
+        self.queued() >= self.queue_capacity
+    }
+}
+
+/// A place in the [`BlockingPool`] queue, see [`BlockingPool::reserve`]
+#[derive(Debug)]
+pub struct Reservation {
+    /// Taken once the task has been handed over
+    pool: Option<BlockingPool>,
+}
+
+impl Reservation {
+    /// Runs `f` on one of the pool threads in the reserved place and resolves
+    /// with its return value
+    pub async fn run<F, T>(mut self, f: F) -> Result<T, PoolError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let Some(pool) = self.pool.take() else {
+            return Err(PoolError::Canceled);
+        };
+        let (tx, rx) = oneshot::channel();
+        let task: Task = Box::new(move || {
+            // The receiver may have gone away if the request was dropped
+            let _ = tx.send(f());
+        });
+
+        // Every task in the channel holds a reservation, so it cannot be full
+        if let Err(e) = pool.sender.try_send(task) {
+            pool.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(match e {
+                TrySendError::Full(_) => PoolError::Saturated,
+                TrySendError::Disconnected(_) => PoolError::Canceled,
+            });
+        }
+
+        rx.await.map_err(|_| PoolError::Canceled)
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            pool.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl fmt::Debug for BlockingPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockingPool")
+            .field("size", &self.size)
+            .field("queue_capacity", &self.queue_capacity)
+            .field("queued", &self.queued())
+            .field("active", &self.active())
+            .finish()
+    }
+}
+
+fn worker_loop(
+    receiver: Arc<Mutex<Receiver<Task>>>,
+    queued: Arc<AtomicUsize>,
+    active: Arc<AtomicUsize>,
+) {
+    loop {
+        // Only hold the lock while waiting for the next task
+        let task = match receiver.lock().unwrap().recv() {
+            Ok(task) => task,
+            Err(_) => break,
+        };
+
+        queued.fetch_sub(1, Ordering::SeqCst);
+        active.fetch_add(1, Ordering::SeqCst);
+        // A panicking task drops its result sender, which the caller sees as
+        // `PoolError::Canceled`; the thread itself keeps serving
+        if panic::catch_unwind(AssertUnwindSafe(task)).is_err() {
+            tracing::error!("Blocking task panicked");
+        }
+        active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservations_are_turned_away_at_capacity() {
+        let pool = BlockingPool::new(1, 2).unwrap();
+        let first = pool.reserve().unwrap();
+        let second = pool.reserve().unwrap();
+        assert_eq!(pool.queued(), 2);
+        assert!(pool.is_saturated());
+        assert!(matches!(pool.reserve(), Err(PoolError::Saturated)));
+        assert!(matches!(futures::executor::block_on(pool.run(|| ())), Err(PoolError::Saturated)));
+
+        // A reservation dropped unused gives its place back
+        drop(first);
+        assert_eq!(pool.queued(), 1);
+        let third = pool.reserve().unwrap();
+
+        // And one that is used gives it back once its task is picked up
+        assert_eq!(futures::executor::block_on(second.run(|| 40 + 2)).unwrap(), 42);
+        assert_eq!(futures::executor::block_on(third.run(|| "done")).unwrap(), "done");
+        assert_eq!(pool.queued(), 0);
+        assert!(!pool.is_saturated());
+    }
+
+    #[test]
+    fn a_pool_without_a_queue_takes_no_work() {
+        let pool = BlockingPool::new(2, 0).unwrap();
+        assert!(pool.is_saturated());
+        assert!(matches!(pool.reserve(), Err(PoolError::Saturated)));
+        assert!(matches!(futures::executor::block_on(pool.run(|| ())), Err(PoolError::Saturated)));
+        assert_eq!(pool.queued(), 0);
+    }
+
+    #[test]
+    fn a_panicking_task_is_canceled_without_losing_the_thread() {
+        let pool = BlockingPool::new(1, 1).unwrap();
+        assert!(matches!(
+            futures::executor::block_on(pool.run(|| panic!("task failed"))),
+            Err(PoolError::Canceled)
+        ));
+        assert_eq!(futures::executor::block_on(pool.run(|| 1)).unwrap(), 1);
+    }
+}
@@ -0,0 +1,144 @@
+use std::io::{self, Read};
+
+use flate2::read::MultiGzDecoder;
+
+use crate::utils::limits::LimitError;
+
+/// First two bytes of every gzip member
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// First four bytes of every zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Suffix of gzip-compressed JSON files, as GH Archive distributes them
+pub const JSON_GZ_SUFFIX: &str = ".json.gz";
+
+/// Name an upload that is a single compressed file is processed under
+pub const COMPRESSED_UPLOAD_ENTRY: &str = "upload.json";
+
+/// Whether an archive entry is a gzip-compressed JSON file
+pub fn is_json_gz(name: &str) -> bool {
+    name.ends_with(JSON_GZ_SUFFIX)
+}
+
+/// Name a `.json.gz` entry is extracted under, without the `.gz`
+pub fn decompressed_name(name: &str) -> &str {
+    name.strip_suffix(".gz").unwrap_or(name)
+}
+
+/// Reader over the JSON in an archive entry, decompressing `.json.gz`
+/// entries. Returns `None` for entries that are not JSON files.
+pub fn json_entry<'a>(name: &str, entry: impl Read + 'a) -> Option<Box<dyn Read + 'a>> {
+    if is_json_gz(name) {
+        Some(Box::new(Compression::Gzip.decoder(entry).ok()?))
+    } else if name.ends_with(".json") {
+        Some(Box::new(entry))
+    } else {
+        None
+    }
+}
+
+/// Compression wrapped around an upload, told apart by its magic bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn detect(head: &[u8]) -> Self {
+        if head.starts_with(&GZIP_MAGIC) {
+            Self::Gzip
+        } else if head.starts_with(&ZSTD_MAGIC) {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+
+    /// Decompresses `reader` as it is read. Gzip files made of several
+    /// members back to back, as `cat a.gz b.gz` produces, are read as one.
+    pub fn decoder<'a>(self, reader: impl Read + 'a) -> io::Result<Decoder<'a>> {
+        let inner: Box<dyn Read + 'a> = match self {
+            Self::None => Box::new(reader),
+            Self::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Self::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        };
+        Ok(Decoder { inner })
+    }
+}
+
+/// Reader returned by [`Compression::decoder`]
+pub struct Decoder<'a> {
+    inner: Box<dyn Read + 'a>,
+}
+
+impl Read for Decoder<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // flate2 reports bad headers, corrupt streams and checksum mismatches
+        // as invalid input and zstd as other errors; they are a broken upload,
+        // not a server fault
+        self.inner.read(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidInput => io::Error::new(io::ErrorKind::InvalidData, e),
+            io::ErrorKind::Other if LimitError::from_io(&e).is_none() => {
+                io::Error::new(io::ErrorKind::InvalidData, e)
+            }
+            _ => e,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn read_all(mut reader: impl Read) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn json_entries_are_selected_by_name() {
+        assert_eq!(read_all(json_entry("a/b.json", &b"[]"[..]).unwrap()).unwrap(), b"[]");
+        let gz = gzip(b"{}");
+        assert_eq!(read_all(json_entry("b.json.gz", &gz[..]).unwrap()).unwrap(), b"{}");
+        assert!(json_entry("notes.txt", &b""[..]).is_none());
+        assert!(json_entry("data.gz", &gz[..]).is_none());
+        assert_eq!(decompressed_name("a/b.json.gz"), "a/b.json");
+        assert_eq!(decompressed_name("a/b.json"), "a/b.json");
+    }
+
+    #[test]
+    fn concatenated_gzip_members_read_as_one() {
+        let mut data = gzip(b"{\"a\":1}\n");
+        data.extend(gzip(b"{\"a\":2}\n"));
+        assert_eq!(Compression::detect(&data), Compression::Gzip);
+        let decoded = read_all(Compression::Gzip.decoder(&data[..]).unwrap()).unwrap();
+        assert_eq!(decoded, b"{\"a\":1}\n{\"a\":2}\n");
+    }
+
+    #[test]
+    fn corrupt_streams_are_invalid_data() {
+        let mut data = gzip(&[b' '; 1000]);
+        let middle = data.len() / 2;
+        data[middle] ^= 0xff;
+        let error = read_all(Compression::Gzip.decoder(&data[..]).unwrap()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn magic_bytes_are_detected() {
+        assert_eq!(Compression::detect(&zstd::encode_all(&b"{}"[..], 0).unwrap()), Compression::Zstd);
+        assert_eq!(Compression::detect(b"{}"), Compression::None);
+    }
+}
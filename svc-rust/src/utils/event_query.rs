@@ -0,0 +1,440 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use regex::Regex;
+use serde::ser::{SerializeMap, Serializer};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error::AppError;
+use crate::types::Event;
+use crate::utils::tabular::Column;
+
+/// Separates the conditions of a `filter` parameter, all of which must hold.
+/// Written as `\;` it stands for itself, so patterns can contain it.
+const CONDITION_SEPARATOR: char = ';';
+
+/// Splits a `filter` parameter into its conditions, unescaping `\;`. Other
+/// backslashes are kept, as patterns use them for their own escapes.
+fn split_conditions(filter: &str) -> Vec<String> {
+    let mut conditions = Vec::new();
+    let mut condition = String::new();
+    let mut chars = filter.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.next_if_eq(&CONDITION_SEPARATOR).is_some() => condition.push(CONDITION_SEPARATOR),
+            CONDITION_SEPARATOR => conditions.push(std::mem::take(&mut condition)),
+            c => condition.push(c),
+        }
+    }
+    conditions.push(condition);
+    conditions
+}
+
+/// Event fields a filter can test, named by their path in the event JSON
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EventField {
+    Id,
+    Type,
+    Public,
+    CreatedAt,
+    ActorId,
+    ActorLogin,
+    RepoId,
+    RepoName,
+    OrgId,
+    OrgLogin,
+    /// Only read from events whose payload has an action, decoding it
+    PayloadAction,
+}
+
+const FIELD_NAMES: &str =
+    "id, type, public, created_at, actor.id, actor.login, repo.id, repo.name, org.id, org.login, payload.action";
+
+/// Value of an event field, borrowed from the event unless it had to be
+/// decoded from the payload
+enum FieldValue<'a> {
+    Text(Cow<'a, str>),
+    Int(i64),
+    Bool(bool),
+    Time(DateTime<Utc>),
+}
+
+impl EventField {
+    fn parse(path: &str) -> Result<Self, AppError> {
+        Ok(match path {
+            "id" => Self::Id,
+            "type" => Self::Type,
+            "public" => Self::Public,
+            "created_at" => Self::CreatedAt,
+            "actor.id" => Self::ActorId,
+            "actor.login" => Self::ActorLogin,
+            "repo.id" => Self::RepoId,
+            "repo.name" => Self::RepoName,
+            "org.id" => Self::OrgId,
+            "org.login" => Self::OrgLogin,
+            "payload.action" => Self::PayloadAction,
+            other => {
+                return Err(AppError::InvalidRequest(format!(
+                    "Unknown filter field {}, expected one of {}",
+                    other, FIELD_NAMES
+                )))
+            }
+        })
+    }
+
+    fn value<'a>(&self, event: &'a Event) -> Option<FieldValue<'a>> {
+        let text = |value: &'a Option<String>| value.as_deref().map(|text| FieldValue::Text(Cow::Borrowed(text)));
+        match self {
+            Self::Id => text(&event.id),
+            Self::Type => text(&event.type_field),
+            Self::Public => Some(FieldValue::Bool(event.public)),
+            Self::CreatedAt => event.created_at.map(FieldValue::Time),
+            Self::ActorId => event.actor.as_ref()?.id.map(FieldValue::Int),
+            Self::ActorLogin => text(&event.actor.as_ref()?.login),
+            Self::RepoId => event
+                .repo
+                .as_ref()?
+                .id
+                .and_then(|id| i64::try_from(id).ok())
+                .map(FieldValue::Int),
+            Self::RepoName => text(&event.repo.as_ref()?.name),
+            Self::OrgId => event.org.as_ref()?.id.map(FieldValue::Int),
+            Self::OrgLogin => text(&event.org.as_ref()?.login),
+            Self::PayloadAction => event.payload()?.action().map(|action| FieldValue::Text(Cow::Owned(action))),
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(self, Self::ActorId | Self::RepoId | Self::OrgId)
+    }
+
+    fn is_text(&self) -> bool {
+        !(self.is_numeric() || matches!(self, Self::Public | Self::CreatedAt))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Eq,
+    Ne,
+    Matches,
+    NotMatches,
+    Ge,
+    Le,
+    Gt,
+    Lt,
+}
+
+impl Operator {
+    /// Longest first, so `>=` is not read as `>` followed by `=`
+    const ALL: [(&'static str, Self); 8] = [
+        ("!=", Self::Ne),
+        ("!~", Self::NotMatches),
+        (">=", Self::Ge),
+        ("<=", Self::Le),
+        ("=", Self::Eq),
+        ("~", Self::Matches),
+        (">", Self::Gt),
+        ("<", Self::Lt),
+    ];
+
+    fn holds(&self, ordering: Ordering) -> bool {
+        match self {
+            Self::Eq => ordering.is_eq(),
+            Self::Ne => ordering.is_ne(),
+            Self::Ge => ordering.is_ge(),
+            Self::Le => ordering.is_le(),
+            Self::Gt => ordering.is_gt(),
+            Self::Lt => ordering.is_lt(),
+            Self::Matches | Self::NotMatches => false,
+        }
+    }
+}
+
+/// Right-hand side of a condition, parsed for the type of its field
+#[derive(Debug, Clone)]
+enum Operand {
+    Text(String),
+    Int(i64),
+    Bool(bool),
+    Time(DateTime<Utc>),
+    Pattern(Regex),
+}
+
+/// Reads an RFC 3339 timestamp, or a `YYYY-MM-DD` date as its midnight UTC
+fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Some(time.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc())
+}
+
+/// One `field op value` test, e.g. `repo.name~^rust-lang/`
+#[derive(Debug, Clone)]
+struct Condition {
+    field: EventField,
+    operator: Operator,
+    operand: Operand,
+}
+
+impl Condition {
+    fn parse(condition: &str) -> Result<Self, AppError> {
+        let invalid = |reason: &str| {
+            AppError::InvalidRequest(format!("Invalid filter condition {}: {}", condition, reason))
+        };
+
+        let name_end = condition
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+            .ok_or_else(|| invalid("expected an operator"))?;
+        let (path, rest) = condition.split_at(name_end);
+        let field = EventField::parse(path.trim())?;
+        let rest = rest.trim_start();
+        let (operator, value) = Operator::ALL
+            .iter()
+            .find_map(|(symbol, operator)| rest.strip_prefix(symbol).map(|value| (*operator, value.trim())))
+            .ok_or_else(|| invalid("expected one of =, !=, ~, !~, >=, <=, >, <"))?;
+
+        let operand = match (operator, field) {
+            (Operator::Matches | Operator::NotMatches, field) if !field.is_text() => {
+                return Err(invalid("only text fields can be matched against a pattern"))
+            }
+            (Operator::Matches | Operator::NotMatches, _) => {
+                Operand::Pattern(Regex::new(value).map_err(|e| invalid(&e.to_string()))?)
+            }
+            (Operator::Eq | Operator::Ne, EventField::Public) => {
+                Operand::Bool(value.parse().map_err(|_| invalid("expected true or false"))?)
+            }
+            (_, EventField::Public) => return Err(invalid("public can only be tested with = or !=")),
+            (_, EventField::CreatedAt) => Operand::Time(parse_time(value).ok_or_else(|| {
+                invalid("expected an RFC 3339 timestamp or a YYYY-MM-DD date")
+            })?),
+            (_, field) if field.is_numeric() => {
+                Operand::Int(value.parse().map_err(|_| invalid("expected an integer"))?)
+            }
+            _ => Operand::Text(value.to_string()),
+        };
+
+        Ok(Self {
+            field,
+            operator,
+            operand,
+        })
+    }
+
+    /// Events without the field only pass negated conditions
+    fn matches(&self, event: &Event) -> bool {
+        let Some(value) = self.field.value(event) else {
+            return matches!(self.operator, Operator::Ne | Operator::NotMatches);
+        };
+        match (&self.operand, value) {
+            (Operand::Pattern(pattern), FieldValue::Text(text)) => {
+                pattern.is_match(&text) == (self.operator == Operator::Matches)
+            }
+            (Operand::Text(expected), FieldValue::Text(text)) => {
+                self.operator.holds(text.as_ref().cmp(expected.as_str()))
+            }
+            (Operand::Int(expected), FieldValue::Int(number)) => self.operator.holds(number.cmp(expected)),
+            (Operand::Bool(expected), FieldValue::Bool(flag)) => self.operator.holds(flag.cmp(expected)),
+            (Operand::Time(expected), FieldValue::Time(time)) => self.operator.holds(time.cmp(expected)),
+            _ => false,
+        }
+    }
+}
+
+/// Conditions an event has to meet to reach the extractors, from the
+/// `filter` parameter, e.g. `type=PushEvent;created_at>=2024-01-01`. A `;`
+/// inside a condition is written `\;`, e.g. `repo.name~^a\;b`.
+///
+/// Text fields compare as strings, and `~` matches a regular expression
+/// anywhere in them. `created_at` compares as a point in time, against an RFC
+/// 3339 timestamp or a date, which stands for midnight UTC.
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    conditions: Vec<Condition>,
+}
+
+impl EventFilter {
+    pub fn parse(filter: Option<&str>) -> Result<Self, AppError> {
+        let conditions = split_conditions(filter.unwrap_or_default())
+            .iter()
+            .map(|condition| condition.trim())
+            .filter(|condition| !condition.is_empty())
+            .map(Condition::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { conditions })
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        self.conditions.iter().all(|condition| condition.matches(event))
+    }
+}
+
+/// Fields kept in every output record, from the `fields` parameter, e.g.
+/// `fields=id,login`
+#[derive(Debug, Clone)]
+pub struct Projection {
+    fields: Vec<String>,
+}
+
+impl Projection {
+    /// Parses a comma-separated field list; an empty or missing list keeps
+    /// whole records
+    pub fn parse(fields: Option<&str>) -> Option<Self> {
+        let mut kept: Vec<String> = Vec::new();
+        for field in fields.unwrap_or_default().split(',').map(str::trim) {
+            if !field.is_empty() && !kept.iter().any(|kept| kept == field) {
+                kept.push(field.to_string());
+            }
+        }
+        (!kept.is_empty()).then_some(Self { fields: kept })
+    }
+
+    /// The columns of an extractor's records that are kept, in the order
+    /// they were asked for. Fails if the records have no such field.
+    pub fn columns(&self, extractor: &str, columns: &[Column]) -> Result<Vec<Column>, AppError> {
+        self.fields
+            .iter()
+            .map(|field| {
+                columns
+                    .iter()
+                    .find(|column| column.name == field)
+                    .copied()
+                    .ok_or_else(|| {
+                        let names: Vec<_> = columns.iter().map(|column| column.name).collect();
+                        AppError::InvalidRequest(format!(
+                            "Field {} is not produced by {}, expected one of {}",
+                            field,
+                            extractor,
+                            names.join(", ")
+                        ))
+                    })
+            })
+            .collect()
+    }
+
+    /// Cuts a record down to the kept fields, in the order they were asked for
+    pub fn apply(&self, record: &impl Serialize) -> Result<ProjectedRecord, AppError> {
+        let value = serde_json::to_value(record)
+            .map_err(|e| AppError::Internal(format!("Failed to serialize output: {}", e)))?;
+        let Value::Object(mut fields) = value else {
+            return Err(AppError::Internal(
+                "Projected output needs records that serialize as objects".to_string(),
+            ));
+        };
+        Ok(ProjectedRecord(
+            self.fields
+                .iter()
+                .filter_map(|field| fields.remove_entry(field))
+                .collect(),
+        ))
+    }
+}
+
+/// Record left by a [`Projection`]. Serializes as an object with its fields
+/// in projection order, which a `serde_json::Map` would sort.
+pub struct ProjectedRecord(Vec<(String, Value)>);
+
+impl Serialize for ProjectedRecord {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (field, value) in &self.0 {
+            map.serialize_entry(field, value)?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::tabular::ColumnType;
+    use serde_json::json;
+
+    fn event(value: Value) -> Event {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn push_event() -> Event {
+        event(json!({
+            "id": "1",
+            "type": "PushEvent",
+            "public": true,
+            "created_at": "2024-03-01T12:00:00Z",
+            "actor": {"id": 7, "login": "octocat"},
+            "repo": {"id": 42, "name": "rust-lang/rust;nightly"},
+            "payload": {"push_id": 1}
+        }))
+    }
+
+    fn matches(filter: &str, event: &Event) -> bool {
+        EventFilter::parse(Some(filter)).unwrap().matches(event)
+    }
+
+    #[test]
+    fn every_condition_has_to_hold() {
+        let event = push_event();
+        assert!(matches("", &event));
+        assert!(matches("type=PushEvent; actor.id >= 7 ;", &event));
+        assert!(matches("repo.name~^rust-lang/;public=true;created_at<2024-03-02", &event));
+        assert!(!matches("type=PushEvent;actor.id>7", &event));
+        assert!(!matches("created_at>2024-03-01T12:00:00Z", &event));
+    }
+
+    #[test]
+    fn escaped_separators_stay_in_their_condition() {
+        let event = push_event();
+        assert_eq!(split_conditions(r"a=1;b~x\;y;c~\d"), vec!["a=1", "b~x;y", r"c~\d"]);
+        assert!(matches(r"repo.name~rust\;nightly$;actor.login~^\w+$", &event));
+        assert!(matches(r"repo.name=rust-lang/rust\;nightly", &event));
+        assert!(!matches(r"repo.name~rust\;stable", &event));
+    }
+
+    #[test]
+    fn missing_fields_only_pass_negated_conditions() {
+        let event = push_event();
+        assert!(!matches("org.login=github", &event));
+        assert!(matches("org.login!=github", &event));
+        assert!(matches("org.login!~git", &event));
+        assert!(!matches("payload.action=opened", &event));
+    }
+
+    #[test]
+    fn invalid_conditions_are_rejected() {
+        for filter in [
+            "actor.name=octocat",
+            "type",
+            "actor.id~7",
+            "public>true",
+            "actor.id=seven",
+            "created_at>=yesterday",
+            "repo.name~(",
+        ] {
+            assert!(
+                matches!(EventFilter::parse(Some(filter)), Err(AppError::InvalidRequest(_))),
+                "{}",
+                filter
+            );
+        }
+    }
+
+    #[test]
+    fn projections_keep_the_fields_asked_for_in_order() {
+        assert!(Projection::parse(None).is_none());
+        assert!(Projection::parse(Some(" , ")).is_none());
+
+        let projection = Projection::parse(Some("login, id,login,,missing")).unwrap();
+        let record = projection.apply(&json!({"id": 7, "login": "octocat", "url": "u"})).unwrap();
+        assert_eq!(serde_json::to_string(&record).unwrap(), r#"{"login":"octocat","id":7}"#);
+
+        let columns = [Column::new("id", ColumnType::Int), Column::new("login", ColumnType::String)];
+        assert!(matches!(
+            projection.columns("actors", &columns),
+            Err(AppError::InvalidRequest(message)) if message.contains("missing")
+        ));
+        let kept = Projection::parse(Some("login,id")).unwrap().columns("actors", &columns).unwrap();
+        assert_eq!(kept, vec![columns[1], columns[0]]);
+    }
+}
@@ -0,0 +1,132 @@
+use std::path::{Component, Path, PathBuf};
+
+use serde::Serialize;
+
+/// An archive entry that was not extracted, and why
+#[derive(Debug, Clone, Serialize)]
+pub struct SkippedEntry {
+    pub name: String,
+    pub reason: String,
+}
+
+/// What happened to the entries of an uploaded archive
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExtractionReport {
+    pub files_extracted: usize,
+    pub skipped: Vec<SkippedEntry>,
+}
+
+impl ExtractionReport {
+    pub fn skip(&mut self, name: &str, reason: impl Into<String>) {
+        let reason = reason.into();
+        tracing::warn!("Skipping archive entry {}: {}", name, reason);
+        self.skipped.push(SkippedEntry {
+            name: name.to_string(),
+            reason,
+        });
+    }
+}
+
+/// Resolves an archive entry name to a path under `root`.
+///
+/// Both `/` and `\` are treated as separators so archives built on Windows
+/// keep their folder structure. Absolute paths and `..` components are
+/// rejected rather than stripped, since silently relocating an entry would
+/// hide a malicious archive. The error is the reason reported to the client.
+pub fn resolve_entry_path(root: &Path, name: &str) -> Result<PathBuf, String> {
+    let normalized = name.replace('\\', "/");
+    let bytes = normalized.as_bytes();
+    let has_drive_letter = bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':';
+    if normalized.starts_with('/') || has_drive_letter {
+        return Err("absolute paths are not allowed".to_string());
+    }
+
+    let mut resolved = root.to_path_buf();
+    let mut depth = 0;
+    for component in Path::new(&normalized).components() {
+        match component {
+            Component::Normal(part) => {
+                resolved.push(part);
+                depth += 1;
+            }
+            Component::CurDir => {}
+            Component::ParentDir => {
+                return Err("parent directory references are not allowed".to_string());
+            }
+            // Drive letters and UNC prefixes on Windows, `/` elsewhere
+            Component::Prefix(_) | Component::RootDir => {
+                return Err("absolute paths are not allowed".to_string());
+            }
+        }
+    }
+
+    if depth == 0 {
+        return Err("entry has an empty path".to_string());
+    }
+
+    Ok(resolved)
+}
+
+/// Lists the `.json` files under `dir`, descending into subdirectories, in a
+/// stable order
+pub fn json_files(dir: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+
+    while let Some(current) = pending.pop() {
+        for entry in std::fs::read_dir(&current)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let path = entry.path();
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if file_type.is_file()
+                && path.extension().and_then(|s| s.to_str()) == Some("json")
+            {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(name: &str) -> String {
+        resolve_entry_path(Path::new("/work/extracted"), name).unwrap_err()
+    }
+
+    #[test]
+    fn parent_references_are_rejected() {
+        for name in ["../x.json", "a/../../x.json", "a/b/../c.json", "..\\x.json", "a\\..\\..\\x.json"] {
+            assert_eq!(rejected(name), "parent directory references are not allowed", "{}", name);
+        }
+    }
+
+    #[test]
+    fn absolute_paths_are_rejected() {
+        for name in ["/etc/x.json", "\\etc\\x.json", "C:\\x.json", "c:/x.json", "\\\\server\\share\\x.json"] {
+            assert_eq!(rejected(name), "absolute paths are not allowed", "{}", name);
+        }
+    }
+
+    #[test]
+    fn names_without_a_file_are_rejected() {
+        for name in ["", ".", "./", "././."] {
+            assert_eq!(rejected(name), "entry has an empty path", "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn nested_paths_resolve_under_the_root() {
+        let root = Path::new("/work/extracted");
+        let resolved = resolve_entry_path(root, "2024/./01\\events.json").unwrap();
+        assert_eq!(resolved, root.join("2024").join("01").join("events.json"));
+        assert!(resolved.starts_with(root));
+        assert_eq!(resolve_entry_path(root, "dir/").unwrap(), root.join("dir"));
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::config::AppConfig;
+use crate::error::AppError;
+use crate::types::{Actor, Event, Org, Repo};
+use crate::utils::actor_dedup::{ActorActivity, ActorAggregate, ActorAggregator, ActorStats};
+use crate::utils::output::OutputSink;
+use crate::utils::tabular::{Column, ColumnType};
+use crate::utils::time_series::{RepoSeriesExtractor, TimeBucket, TypeSeriesExtractor};
+
+/// Pulls one kind of record out of the events of an upload.
+///
+/// An extractor sees every event of a job, across all of its files, and
+/// writes what it finds to its own output file through `out`. Records that can
+/// be emitted as soon as they are seen should be, so the large pipeline keeps
+/// its memory bounded; aggregates are written from [`Extractor::finish`].
+pub trait Extractor: Send {
+    /// Name used to select the extractor and to name its output file
+    fn name(&self) -> &'static str;
+
+    /// Looks at one event
+    fn extract(&mut self, event: &Event, out: &mut OutputSink) -> Result<(), AppError>;
+
+    /// Writes anything held back until every event has been seen
+    fn finish(&mut self, _out: &mut OutputSink) -> Result<(), AppError> {
+        Ok(())
+    }
+}
+
+/// The extractors that can be requested with `extract=`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExtractorKind {
+    Actors,
+    UniqueActors,
+    ActorActivity,
+    Repos,
+    Orgs,
+    EventTypes,
+    TypeSeries,
+    RepoSeries,
+}
+
+impl ExtractorKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Actors => "actors",
+            Self::UniqueActors => "unique_actors",
+            Self::ActorActivity => "actor_activity",
+            Self::Repos => "repos",
+            Self::Orgs => "orgs",
+            Self::EventTypes => "event_types",
+            Self::TypeSeries => "type_series",
+            Self::RepoSeries => "repo_series",
+        }
+    }
+
+    /// Columns of the records the extractor writes, for CSV and Parquet
+    /// output and for checking `fields=` against
+    pub fn columns(&self) -> Vec<Column> {
+        match self {
+            Self::Actors => Actor::columns(),
+            Self::UniqueActors => ActorStats::columns(),
+            Self::ActorActivity => ActorActivity::columns(),
+            Self::Repos => Repo::columns(),
+            Self::Orgs => Org::columns(),
+            Self::EventTypes => vec![
+                Column::new("type", ColumnType::String),
+                Column::new("count", ColumnType::Int),
+            ],
+            Self::TypeSeries => vec![
+                Column::new("bucket", ColumnType::String),
+                Column::new("type", ColumnType::String),
+                Column::new("count", ColumnType::Int),
+            ],
+            Self::RepoSeries => vec![
+                Column::new("bucket", ColumnType::String),
+                Column::new("repo_id", ColumnType::UInt),
+                Column::new("repo_name", ColumnType::String),
+                Column::new("count", ColumnType::Int),
+            ],
+        }
+    }
+
+    pub fn create(&self, options: &ExtractorOptions, scratch_dir: &Path) -> Box<dyn Extractor> {
+        match self {
+            Self::Actors => Box::new(ActorsExtractor),
+            Self::UniqueActors => Box::new(ActorAggregator::<ActorStats>::new(
+                self.name(),
+                options.max_actors_in_memory,
+                scratch_dir,
+            )),
+            Self::ActorActivity => Box::new(ActorAggregator::<ActorActivity>::new(
+                self.name(),
+                options.max_actors_in_memory,
+                scratch_dir,
+            )),
+            Self::Repos => Box::new(ReposExtractor::default()),
+            Self::Orgs => Box::new(OrgsExtractor::default()),
+            Self::EventTypes => Box::new(EventTypesExtractor::default()),
+            Self::TypeSeries => Box::new(TypeSeriesExtractor::new(options.bucket)),
+            Self::RepoSeries => Box::new(RepoSeriesExtractor::new(options.bucket)),
+        }
+    }
+
+    /// Parses a comma-separated list such as `actors,repos`. An empty or
+    /// missing list selects the actors extractor alone.
+    pub fn parse_list(list: Option<&str>) -> Result<Vec<Self>, AppError> {
+        let mut kinds = Vec::new();
+        for name in list.unwrap_or_default().split(',').map(str::trim) {
+            if name.is_empty() {
+                continue;
+            }
+            let kind = name.parse()?;
+            if !kinds.contains(&kind) {
+                kinds.push(kind);
+            }
+        }
+        if kinds.is_empty() {
+            kinds.push(Self::Actors);
+        }
+        Ok(kinds)
+    }
+}
+
+impl FromStr for ExtractorKind {
+    type Err = AppError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "actors" => Ok(Self::Actors),
+            "unique_actors" => Ok(Self::UniqueActors),
+            "actor_activity" => Ok(Self::ActorActivity),
+            "repos" => Ok(Self::Repos),
+            "orgs" => Ok(Self::Orgs),
+            "event_types" => Ok(Self::EventTypes),
+            "type_series" => Ok(Self::TypeSeries),
+            "repo_series" => Ok(Self::RepoSeries),
+            other => Err(AppError::InvalidRequest(format!(
+                "Unknown extractor {}, expected one of actors, unique_actors, actor_activity, repos, orgs, event_types, type_series, repo_series",
+                other
+            ))),
+        }
+    }
+}
+
+/// What to extract for a job, and the limits the extractors work within
+#[derive(Debug, Clone)]
+pub struct ExtractorOptions {
+    pub kinds: Vec<ExtractorKind>,
+    pub max_actors_in_memory: usize,
+    /// Bucket width of the time series extractors
+    pub bucket: TimeBucket,
+}
+
+impl ExtractorOptions {
+    pub fn from_config(config: &AppConfig, kinds: Vec<ExtractorKind>) -> Self {
+        Self {
+            kinds,
+            max_actors_in_memory: config.max_actors_in_memory,
+            bucket: TimeBucket::default(),
+        }
+    }
+}
+
+/// Emits the actor of every event
+pub struct ActorsExtractor;
+
+impl Extractor for ActorsExtractor {
+    fn name(&self) -> &'static str {
+        "actors"
+    }
+
+    fn extract(&mut self, event: &Event, out: &mut OutputSink) -> Result<(), AppError> {
+        match &event.actor {
+            Some(actor) => out.push(actor),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Emits each repository once, the first time an event references it
+#[derive(Default)]
+pub struct ReposExtractor {
+    seen: HashSet<String>,
+}
+
+impl Extractor for ReposExtractor {
+    fn name(&self) -> &'static str {
+        "repos"
+    }
+
+    fn extract(&mut self, event: &Event, out: &mut OutputSink) -> Result<(), AppError> {
+        let Some(repo) = &event.repo else {
+            return Ok(());
+        };
+        let key = match (&repo.id, &repo.name) {
+            (Some(id), _) => id.to_string(),
+            (None, Some(name)) => name.clone(),
+            (None, None) => return Ok(()),
+        };
+        if self.seen.insert(key) {
+            out.push(repo)?;
+        }
+        Ok(())
+    }
+}
+
+/// Emits each organization once, the first time an event references it
+#[derive(Default)]
+pub struct OrgsExtractor {
+    seen: HashSet<String>,
+}
+
+impl Extractor for OrgsExtractor {
+    fn name(&self) -> &'static str {
+        "orgs"
+    }
+
+    fn extract(&mut self, event: &Event, out: &mut OutputSink) -> Result<(), AppError> {
+        let Some(org) = &event.org else {
+            return Ok(());
+        };
+        let key = match (&org.id, &org.login) {
+            (Some(id), _) => id.to_string(),
+            (None, Some(login)) => login.clone(),
+            (None, None) => return Ok(()),
+        };
+        if self.seen.insert(key) {
+            out.push(org)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts events per `type`, written as `{"type", "count"}` entries once all
+/// events have been seen
+#[derive(Default)]
+pub struct EventTypesExtractor {
+    counts: BTreeMap<String, u64>,
+}
+
+impl Extractor for EventTypesExtractor {
+    fn name(&self) -> &'static str {
+        "event_types"
+    }
+
+    fn extract(&mut self, event: &Event, _out: &mut OutputSink) -> Result<(), AppError> {
+        let event_type = event.type_field.as_deref().unwrap_or("unknown");
+        *self.counts.entry(event_type.to_string()).or_default() += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut OutputSink) -> Result<(), AppError> {
+        for (event_type, count) in &self.counts {
+            out.push(&json!({ "type": event_type, "count": count }))?;
+        }
+        Ok(())
+    }
+}
+
+/// What one extractor produced for a job
+#[derive(Debug, Clone, Serialize)]
+pub struct ExtractorOutput {
+    pub extractor: &'static str,
+    pub file: String,
+    pub items: usize,
+}
+
+/// The extractors selected for a job, each paired with the sink of its
+/// output
+pub struct ExtractorSet {
+    extractors: Vec<(Box<dyn Extractor>, OutputSink, String)>,
+}
+
+impl ExtractorSet {
+    /// Creates the selected extractors and opens a sink for each with
+    /// `open`, which returns the sink along with the name of the output
+    pub fn create(
+        options: &ExtractorOptions,
+        scratch_dir: &Path,
+        open: impl Fn(ExtractorKind) -> Result<(OutputSink, String), AppError>,
+    ) -> Result<Self, AppError> {
+        let extractors = options
+            .kinds
+            .iter()
+            .map(|kind| {
+                let (sink, file) = open(*kind)?;
+                Ok((kind.create(options, scratch_dir), sink, file))
+            })
+            .collect::<Result<Vec<_>, AppError>>()?;
+        Ok(Self { extractors })
+    }
+
+    /// Hands one event to every extractor
+    pub fn extract(&mut self, event: &Event) -> Result<(), AppError> {
+        for (extractor, sink, _) in &mut self.extractors {
+            extractor.extract(event, sink)?;
+        }
+        Ok(())
+    }
+
+    /// Lets every extractor write its aggregates and closes the outputs
+    pub fn finish(self) -> Result<Vec<ExtractorOutput>, AppError> {
+        self.extractors
+            .into_iter()
+            .map(|(mut extractor, mut sink, file)| {
+                extractor.finish(&mut sink)?;
+                Ok(ExtractorOutput {
+                    extractor: extractor.name(),
+                    file,
+                    items: sink.close()?,
+                })
+            })
+            .collect()
+    }
+}
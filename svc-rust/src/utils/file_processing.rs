@@ -1,70 +1,530 @@
-use std::path::{Path, PathBuf};
 use std::fs::File;
 use actix_multipart::Multipart;
 use futures::StreamExt;
+use zip::result::ZipError;
 use zip::ZipArchive;
-use std::io::{BufReader, Write};
+use std::io::{self, BufReader, Cursor, Read, Seek, Write};
+use std::path::Path;
+use tar::EntryType;
 
-use crate::config::AppConfig;
+use crate::error::AppError;
+use crate::metrics::METRICS;
+use crate::utils::extraction::{self, ExtractionReport};
+use crate::utils::compression::{self, Compression, COMPRESSED_UPLOAD_ENTRY};
+use crate::utils::json_processing::Rewound;
+use crate::utils::limits::{ArchiveBudget, ArchiveLimits};
+use crate::workspace::Workspace;
 
-pub async fn save_multipart_file(mut payload: Multipart, mut file: File) -> Result<(), Box<dyn std::error::Error>> {
+/// Multipart field that selects extractors, as an alternative to the
+/// `extract` query parameter
+pub const EXTRACT_FIELD: &str = "extract";
+
+/// Longest value accepted for a text form field
+const MAX_FORM_FIELD_BYTES: usize = 1024;
+
+/// Text fields sent alongside the archive in a multipart upload
+#[derive(Debug, Default)]
+pub struct UploadForm {
+    pub extract: Option<String>,
+}
+
+/// Writes the upload to `file`, stopping as soon as it grows past the
+/// configured maximum upload size. Text fields are collected instead of being
+/// written to the file.
+pub async fn save_multipart_file(
+    mut payload: Multipart,
+    mut file: File,
+    limits: &ArchiveLimits,
+) -> Result<UploadForm, AppError> {
+    let mut form = UploadForm::default();
+    let mut received = 0;
     while let Some(item) = payload.next().await {
         let mut field = item?;
+        if field.name() == EXTRACT_FIELD {
+            form.extract = Some(read_text_field(&mut field).await?);
+            continue;
+        }
         while let Some(chunk) = field.next().await {
             let data = chunk?;
+            received += data.len() as u64;
+            METRICS.upload_bytes.inc_by(data.len() as u64);
+            limits.check_upload(received)?;
             file.write_all(&data)?;
         }
     }
-    Ok(())
+    Ok(form)
+}
+
+async fn read_text_field(field: &mut actix_multipart::Field) -> Result<String, AppError> {
+    let mut value = Vec::new();
+    while let Some(chunk) = field.next().await {
+        value.extend_from_slice(&chunk?);
+        if value.len() > MAX_FORM_FIELD_BYTES {
+            return Err(AppError::InvalidRequest(format!(
+                "Form field {} is longer than {} bytes",
+                field.name(),
+                MAX_FORM_FIELD_BYTES
+            )));
+        }
+    }
+    String::from_utf8(value)
+        .map_err(|_| AppError::InvalidRequest(format!("Form field {} is not valid UTF-8", field.name())))
+}
+
+/// How many bytes of an upload are looked at to tell its format. The tar
+/// marker sits at offset 257, and for compressed uploads this is well enough
+/// to decompress the first tar header.
+const SNIFF_BYTES: u64 = 4096;
+
+/// Offset and value of the marker in the first header of a tar archive,
+/// `ustar` in both the POSIX and GNU flavours
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// The kinds of upload that can be extracted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    /// A tar archive, plain or compressed as `.tar.gz` or `.tar.zst`
+    Tar(Compression),
+    /// A single compressed JSON file, such as a GH Archive `.json.gz`
+    Compressed(Compression),
+}
+
+impl ArchiveFormat {
+    /// Tells the format of an upload from its magic bytes, looking inside
+    /// gzip and zstd data to tell a compressed tarball from a single file.
+    /// Returns `None` when the upload matches none of them.
+    ///
+    /// The returned reader yields the whole upload again, the bytes looked at
+    /// included.
+    pub fn sniff<R: Read>(mut reader: R) -> io::Result<(Option<Self>, Rewound<R>)> {
+        let mut head = Vec::new();
+        (&mut reader).take(SNIFF_BYTES).read_to_end(&mut head)?;
+        let format = Self::from_head(&head);
+        Ok((format, Cursor::new(head).chain(reader)))
+    }
+
+    fn from_head(head: &[u8]) -> Option<Self> {
+        if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+            return Some(Self::Zip);
+        }
+        match Compression::detect(head) {
+            Compression::None if is_tar_header(head) => Some(Self::Tar(Compression::None)),
+            Compression::None => None,
+            compression => {
+                // Decompress what there is of the head; running out of input
+                // part way is expected
+                let mut decompressed = Vec::new();
+                if let Ok(decoder) = compression.decoder(head) {
+                    let _ = decoder
+                        .take(TAR_MAGIC_OFFSET as u64 + TAR_MAGIC.len() as u64)
+                        .read_to_end(&mut decompressed);
+                }
+                if is_tar_header(&decompressed) {
+                    Some(Self::Tar(compression))
+                } else {
+                    Some(Self::Compressed(compression))
+                }
+            }
+        }
+    }
+}
+
+fn is_tar_header(head: &[u8]) -> bool {
+    head.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len()) == Some(TAR_MAGIC)
 }
 
-pub async fn validate_and_uncompress_zip(
-    config: &AppConfig,
-    file_path: &Path,
-) -> Result<(), Box<dyn std::error::Error>> {
+/// Extracts the uploaded archive into the workspace extract directory and
+/// reports which entries were written or skipped.
+///
+/// The format is sniffed from the upload, see [`ArchiveFormat`]. Entries are
+/// checked against `limits` before they are written: for ZIP archives all of
+/// them up front from the central directory, for tar archives each one from
+/// its header as the archive is read. Entries are only ever written below the
+/// extract directory: links, absolute paths and `..` components are skipped.
+/// `.json.gz` entries are decompressed as they are extracted, and an upload
+/// that is a single compressed file is decompressed into one JSON file. This
+/// does blocking file I/O; run it on the `BlockingPool`.
+pub fn validate_and_extract_archive(
+    workspace: &Workspace,
+    limits: ArchiveLimits,
+) -> Result<ExtractionReport, AppError> {
+    let _timer = METRICS.extraction_duration.start_timer();
+    let file_path = workspace.upload_path.as_path();
+
     // Check if the file exists and is readable
     if !file_path.exists() {
-        return Err("File does not exist".into());
+        return Err(AppError::Internal("File does not exist".to_string()));
     }
     if !file_path.is_file() {
-        return Err("Path is not a file".into());
+        return Err(AppError::Internal("Path is not a file".to_string()));
     }
 
-    let file = File::open(file_path)?;
-    let reader = BufReader::new(file);
-    let mut archive = ZipArchive::new(reader)?;
+    let (format, reader) = ArchiveFormat::sniff(BufReader::new(File::open(file_path)?))?;
+    tracing::debug!("Job {} uploaded {:?}", workspace.job_id, format);
+    match format {
+        Some(ArchiveFormat::Tar(compression)) => extract_tar(workspace, compression, reader, limits),
+        Some(ArchiveFormat::Compressed(compression)) => uncompress_file(workspace, compression, reader, limits),
+        // ZIP archives may also have data in front of the first entry, so
+        // anything unrecognized is given to the ZIP reader to reject
+        Some(ArchiveFormat::Zip) | None => {
+            let mut reader = reader.into_inner().1;
+            reader.rewind()?;
+            extract_zip(workspace, reader, limits)
+        }
+    }
+}
+
+fn extract_zip(
+    workspace: &Workspace,
+    reader: BufReader<File>,
+    limits: ArchiveLimits,
+) -> Result<ExtractionReport, AppError> {
+    let mut archive = ZipArchive::new(reader).map_err(|e| match e {
+        ZipError::Io(e) => AppError::from(e),
+        // No central directory at all: this is not a ZIP archive
+        other => AppError::UnsupportedType(other.to_string()),
+    })?;
 
     // Check if the ZIP archive is empty
     if archive.is_empty() {
-        return Err("ZIP archive is empty".into());
+        return Err(AppError::Extraction("ZIP archive is empty".to_string()));
+    }
+
+    // Reject oversized archives and zip bombs up front
+    let mut budget = ArchiveBudget::new(limits);
+    for i in 0..archive.len() {
+        let file = archive.by_index_raw(i)?;
+        budget.admit(file.name(), file.size(), file.compressed_size())?;
     }
 
     // Uncompress files
+    let mut report = ExtractionReport::default();
     for i in 0..archive.len() {
         let mut file = archive.by_index(i)?;
-        if file.size() == 0 {
-            return Err(format!("File {} is corrupted", file.name()).into());
+        let name = file.name().to_string();
+
+        if file.is_symlink() {
+            report.skip(&name, "symbolic links are not supported");
+            continue;
+        }
+        if file.is_dir() {
+            match extraction::resolve_entry_path(&workspace.extract_dir, &name) {
+                Ok(path) => std::fs::create_dir_all(&path)?,
+                Err(reason) => report.skip(&name, reason),
+            }
+            continue;
         }
-        let outpath = match file.enclosed_name() {
-            Some(path) => path.to_owned(),
-            None => continue,
-        };
 
-        if file.name().ends_with('/') {
-            // Create directories
-            std::fs::create_dir_all(&outpath)?;
-        } else {
-            // Create the output file
-            if let Some(p) = outpath.parent() {
-                if !p.exists() {
-                    std::fs::create_dir_all(p)?;
-                }
+        let (size, compressed_size) = (file.size(), file.compressed_size());
+        write_entry(workspace, &budget, &mut report, &name, size, compressed_size, &mut file)?;
+    }
+
+    Ok(report)
+}
+
+fn extract_tar(
+    workspace: &Workspace,
+    compression: Compression,
+    reader: impl Read,
+    limits: ArchiveLimits,
+) -> Result<ExtractionReport, AppError> {
+    let mut budget = ArchiveBudget::new(limits);
+    let mut report = ExtractionReport::default();
+    let reader = compression.decoder(budget.count_compressed(reader))?;
+    for_each_tar_entry(reader, &mut budget, &mut report, |budget, report, name, size, entry| {
+        write_entry(workspace, budget, report, name, size, size, entry)
+    })?;
+    Ok(report)
+}
+
+/// Writes one file entry below the extract directory, decompressing it if it
+/// is a `.json.gz` file. Entries whose name would leave the extract
+/// directory are skipped.
+fn write_entry(
+    workspace: &Workspace,
+    budget: &ArchiveBudget,
+    report: &mut ExtractionReport,
+    name: &str,
+    size: u64,
+    compressed_size: u64,
+    entry: &mut dyn Read,
+) -> Result<(), AppError> {
+    let gzipped = compression::is_json_gz(name);
+    let outname = if gzipped { compression::decompressed_name(name) } else { name };
+    let outpath = match extraction::resolve_entry_path(&workspace.extract_dir, outname) {
+        Ok(path) => path,
+        Err(reason) => {
+            report.skip(name, reason);
+            return Ok(());
+        }
+    };
+    if size == 0 {
+        return Err(AppError::Extraction(format!("File {} is corrupted", name)));
+    }
+
+    if let Some(parent) = outpath.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut reader: Box<dyn Read> = if gzipped {
+        Box::new(Compression::Gzip.decoder(entry)?)
+    } else {
+        Box::new(entry)
+    };
+    let mut outfile = File::create(&outpath)?;
+    budget.copy_entry(name, size, compressed_size, &mut reader, &mut outfile)?;
+    report.files_extracted += 1;
+    Ok(())
+}
+
+/// Walks the entries of a tar archive as it is read and hands every regular
+/// file to `on_entry`, along with the budget, the report, its name and its
+/// size.
+///
+/// Each entry is checked against `budget` from its header before it is read.
+/// Tar entries are not compressed one by one, so their own compression
+/// ratio is only checked for `.json.gz` entries, once the callback
+/// decompresses them. A compressed tarball is held to the ratio as a whole
+/// when `reader` decompresses input counted by
+/// [`ArchiveBudget::count_compressed`]. Links, special files and names that
+/// would leave the extract directory are skipped and reported.
+pub fn for_each_tar_entry<R, F>(
+    reader: R,
+    budget: &mut ArchiveBudget,
+    report: &mut ExtractionReport,
+    mut on_entry: F,
+) -> Result<(), AppError>
+where
+    R: Read,
+    F: FnMut(&mut ArchiveBudget, &mut ExtractionReport, &str, u64, &mut dyn Read) -> Result<(), AppError>,
+{
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = 0;
+    for entry in archive.entries().map_err(tar_error)? {
+        let mut entry = entry.map_err(tar_error)?;
+        let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        let size = entry.size();
+        entries += 1;
+        budget.admit(&name, size, size)?;
+
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous => {}
+            EntryType::Directory => continue,
+            EntryType::Symlink | EntryType::Link => {
+                report.skip(&name, "links are not supported");
+                continue;
             }
-            let tmp = PathBuf::from(config.json_dir.to_owned());
-            let mut outfile = File::create(tmp.join(&outpath))?;
-            std::io::copy(&mut file, &mut outfile)?;
+            _ => {
+                report.skip(&name, "only regular files and directories are supported");
+                continue;
+            }
+        }
+        if let Err(reason) = extraction::resolve_entry_path(Path::new(""), &name) {
+            report.skip(&name, reason);
+            continue;
         }
+
+        on_entry(budget, report, &name, size, &mut entry)?;
+        // Whatever the callback left unread is skipped by the next header read
     }
 
+    if entries == 0 {
+        return Err(AppError::Extraction("Tar archive is empty".to_string()));
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// The tar reader raises malformed headers as plain I/O errors; unless they
+/// come from a limit or the decompressor, they mean the archive is damaged
+fn tar_error(error: io::Error) -> AppError {
+    match AppError::from(error) {
+        AppError::Internal(message) => AppError::Extraction(message),
+        other => other,
+    }
+}
+
+/// Decompresses an upload that is a single compressed file into one JSON
+/// file in the extract directory, under the same limits as an archive entry.
+/// Its compression ratio is checked against the upload read so far.
+fn uncompress_file(
+    workspace: &Workspace,
+    compression: Compression,
+    reader: impl Read,
+    limits: ArchiveLimits,
+) -> Result<ExtractionReport, AppError> {
+    let mut budget = ArchiveBudget::new(limits);
+    let mut reader = compression.decoder(budget.count_compressed(reader))?;
+    let mut outfile = File::create(workspace.extract_dir.join(COMPRESSED_UPLOAD_ENTRY))?;
+    let size = budget.copy_entry(COMPRESSED_UPLOAD_ENTRY, 0, 0, &mut reader, &mut outfile)?;
+    if size == 0 {
+        return Err(AppError::Extraction("Compressed file is empty".to_string()));
+    }
+
+    Ok(ExtractionReport {
+        files_extracted: 1,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::limits::LimitError;
+    use flate2::write::GzEncoder;
+
+    fn limits() -> ArchiveLimits {
+        ArchiveLimits {
+            max_upload_bytes: u64::MAX,
+            max_total_bytes: 1 << 20,
+            max_entry_bytes: 1 << 20,
+            max_entries: 10,
+            max_compression_ratio: 100,
+        }
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn tarball(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, *data).unwrap();
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, "link.json", "a.json").unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    /// Reads every JSON entry of a tarball like the tar stream does, and
+    /// returns the decompressed size of each
+    fn read_tar(tarball: &[u8], limits: ArchiveLimits) -> Result<(Vec<(String, u64)>, ExtractionReport), AppError> {
+        let mut budget = ArchiveBudget::new(limits);
+        let mut report = ExtractionReport::default();
+        let mut read = Vec::new();
+        for_each_tar_entry(tarball, &mut budget, &mut report, |budget, _, name, size, entry| {
+            let Some(json) = compression::json_entry(name, entry) else {
+                return Ok(());
+            };
+            let copied = io::copy(&mut budget.limit_entry(name, size, size, json), &mut io::sink())?;
+            read.push((name.to_string(), copied));
+            Ok(())
+        })?;
+        Ok((read, report))
+    }
+
+    #[test]
+    fn tar_entries_are_read_and_links_skipped() {
+        let gz = gzip(br#"{"id":"1"}"#);
+        let tarball = tarball(&[("a.json", b"[]"), ("b.json.gz", &gz), ("notes.txt", b"hi")]);
+        let (read, report) = read_tar(&tarball, limits()).unwrap();
+        assert_eq!(read, [("a.json".to_string(), 2), ("b.json.gz".to_string(), 10)]);
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].name, "link.json");
+    }
+
+    #[test]
+    fn gzipped_tar_entries_are_held_to_the_compression_ratio() {
+        let bomb = gzip(&[b' '; 200_000]);
+        let tarball = tarball(&[("bomb.json.gz", &bomb)]);
+        let error = read_tar(&tarball, limits()).unwrap_err();
+        assert!(
+            matches!(&error, AppError::Limit(LimitError::CompressionRatio { name, .. }) if name == "bomb.json.gz"),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn decompressed_tar_entries_count_towards_the_total() {
+        let entry = gzip(&[b' '; 3000]);
+        let tarball = tarball(&[("a.json.gz", &entry), ("b.json.gz", &entry)]);
+        let limits = ArchiveLimits {
+            max_total_bytes: 5000,
+            ..limits()
+        };
+        let error = read_tar(&tarball, limits).unwrap_err();
+        assert!(matches!(error, AppError::Limit(LimitError::ArchiveTooLarge { limit: 5000 })), "{:?}", error);
+    }
+
+    #[test]
+    fn tar_header_limits_are_checked_before_reading() {
+        let limits = ArchiveLimits {
+            max_entries: 2,
+            ..limits()
+        };
+        let error = read_tar(&tarball(&[("a.json", b"[]"), ("b.json", b"[]")]), limits).unwrap_err();
+        assert!(matches!(error, AppError::Limit(LimitError::TooManyEntries { limit: 2 })), "{:?}", error);
+    }
+
+    #[test]
+    fn archive_formats_are_sniffed() {
+        let tar = tarball(&[("a.json", b"[]")]);
+        let sniff = |data: &[u8]| ArchiveFormat::sniff(data).unwrap().0;
+        assert_eq!(sniff(&tar), Some(ArchiveFormat::Tar(Compression::None)));
+        assert_eq!(sniff(&gzip(&tar)), Some(ArchiveFormat::Tar(Compression::Gzip)));
+        assert_eq!(sniff(&gzip(b"{}")), Some(ArchiveFormat::Compressed(Compression::Gzip)));
+        assert_eq!(sniff(b"PK\x03\x04rest"), Some(ArchiveFormat::Zip));
+        assert_eq!(sniff(b"{}"), None);
+    }
+
+    /// Workspace whose stored upload is `data`
+    fn upload(dir: &Path, data: &[u8]) -> Workspace {
+        let root = dir.to_string_lossy();
+        let (uploads, json) = (format!("{}/uploads", root), format!("{}/json", root));
+        let workspace = Workspace::create(&uploads, &json, "upload").unwrap();
+        std::fs::write(&workspace.upload_path, data).unwrap();
+        workspace
+    }
+
+    #[test]
+    fn compressed_uploads_are_held_to_the_compression_ratio() {
+        let dir = tempfile::tempdir().unwrap();
+        let events = r#"{"id":"1","public":true}"#.repeat(100);
+        for data in [gzip(events.as_bytes()), zstd::encode_all(events.as_bytes(), 0).unwrap()] {
+            let report = validate_and_extract_archive(&upload(dir.path(), &data), limits()).unwrap();
+            assert_eq!(report.files_extracted, 1);
+        }
+
+        for bomb in [gzip(&[b' '; 500_000]), zstd::encode_all(&[b' '; 500_000][..], 0).unwrap()] {
+            let error = validate_and_extract_archive(&upload(dir.path(), &bomb), limits()).unwrap_err();
+            assert!(
+                matches!(error, AppError::Limit(LimitError::UploadCompressionRatio { limit: 100, .. })),
+                "{:?}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn compressed_tarballs_are_held_to_the_compression_ratio() {
+        let dir = tempfile::tempdir().unwrap();
+        let events = r#"{"id":"1","public":true}"#.repeat(100);
+        let tar = tarball(&[("a.json", events.as_bytes())]);
+        for data in [gzip(&tar), zstd::encode_all(&tar[..], 0).unwrap()] {
+            let report = validate_and_extract_archive(&upload(dir.path(), &data), limits()).unwrap();
+            assert_eq!(report.files_extracted, 1);
+        }
+
+        // Each entry is within the entry and total limits, together they
+        // expand far more than the ratio allows
+        let spaces = [b' '; 200_000];
+        let bomb = tarball(&[("a.json", &spaces), ("b.json", &spaces), ("c.json", &spaces)]);
+        for data in [gzip(&bomb), zstd::encode_all(&bomb[..], 0).unwrap()] {
+            let error = validate_and_extract_archive(&upload(dir.path(), &data), limits()).unwrap_err();
+            assert!(
+                matches!(error, AppError::Limit(LimitError::UploadCompressionRatio { limit: 100, .. })),
+                "{:?}",
+                error
+            );
+        }
+    }
+}
@@ -1,97 +1,380 @@
-use std::{
-    fs::File,
-    io::{BufReader, Write},
-    path::{Path, PathBuf},
-};
-
-use crate::{
-    config,
-    types::{Actor, Event},
-};
-
-pub(crate) fn process_json_file(
-    config: &config::AppConfig,
-    file_path: &Path,
-) -> Result<Vec<Actor>, Box<dyn std::error::Error>> {
-    let file = File::open(file_path)?;
-    let reader = BufReader::new(file);
-
-    // Use serde_json to parse the entire file as a JSON array
-    let records: Vec<Event> = serde_json::from_reader(reader)?;
-    let actors: Vec<Actor> = records
-        .into_iter()
-        .filter_map(|record| record.actor)
-        .collect();
-
-    // Prepare output file path
-    let output_path = PathBuf::from(config.json_dir.to_owned() + "actors.json");
-
-    // Open file for writing
-    let mut output_file = File::create(&output_path)?;
-
-    // Write JSON string to file
-    let formatted_json = serde_json::to_string_pretty(&actors)
-        .map_err(|e| format!("Failed to serialize actors: {}", e))?;
-
-    // Write the formatted JSON string
-    output_file
-        .write_all(formatted_json.as_bytes())
-        .map_err(|e| format!("Failed to write to file {}: {}", output_path.display(), e))?;
-
-    output_file.flush()?;
-
-    Ok(actors)
+use std::cell::Cell;
+use std::io::{self, BufRead, BufReader, Chain, Cursor, Read};
+
+use serde::de::{self, Deserializer, IgnoredAny, SeqAccess, Visitor};
+use serde_json::value::RawValue;
+
+use crate::{error::AppError, metrics::METRICS, types::Event};
+
+/// How far into a file [`detect_format`] reads looking for the end of the
+/// first line before settling on [`JsonFormat::Concatenated`]
+const DETECT_LIMIT: usize = 64 * 1024;
+
+/// How the events of one JSON file are laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonFormat {
+    /// A single top-level array of events
+    Array,
+    /// One event per line (JSON Lines), as in GH Archive dumps
+    Ndjson,
+    /// Events written back to back, each possibly spanning several lines
+    Concatenated,
 }
 
-pub fn process_large_json_stream(
-    config: &config::AppConfig,
-    file_path: &Path,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let file = File::open(file_path)?;
-    let reader = BufReader::new(file);
-
-    // Create a streaming JSON deserializer
-    let stream = serde_json::Deserializer::from_reader(reader).into_iter::<serde_json::Value>();
-
-    // Prepare output file
-    let output_path = PathBuf::from(config.json_dir.to_owned() + "actors-stream.json");
-    let mut output_file = File::create(&output_path)?;
-
-    // Write opening bracket for JSON array
-    output_file.write_all(b"[")?;
-
-    // Track whether we've written any actors to handle comma placement
-    let mut first_actor = true;
-
-    // Stream through the JSON records
-    for (index, record_result) in stream.enumerate() {
-        match record_result {
-            Ok(value) => {
-                // Extract actor from JSON value
-                if let Some(actor_value) = value.get("actor") {
-                    // Add comma before non-first items
-                    if !first_actor {
-                        output_file.write_all(b",")?;
-                    } else {
-                        first_actor = false;
-                    }
+/// A reader with the bytes already taken from it put back in front
+pub type Rewound<R> = Chain<Cursor<Vec<u8>>, R>;
+
+/// Tells the layout of a file from its first bytes: a leading `[` is an
+/// array, a first line holding a complete JSON value is NDJSON, and anything
+/// else is read as concatenated values.
+///
+/// Returns the format along with a reader that yields the whole file again,
+/// the bytes looked at included.
+pub fn detect_format<R: Read>(
+    mut reader: R,
+) -> io::Result<(JsonFormat, Rewound<R>)> {
+    let mut head = Vec::new();
+    let mut chunk = [0u8; 8192];
+    let format = loop {
+        let read = match reader.read(&mut chunk) {
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        head.extend_from_slice(&chunk[..read]);
+
+        if let Some(start) = head.iter().position(|b| !b.is_ascii_whitespace()) {
+            if head[start] == b'[' {
+                break JsonFormat::Array;
+            }
+            if let Some(end) = head[start..].iter().position(|&b| b == b'\n') {
+                let first_line = &head[start..start + end];
+                break match serde_json::from_slice::<IgnoredAny>(first_line) {
+                    Ok(_) => JsonFormat::Ndjson,
+                    Err(_) => JsonFormat::Concatenated,
+                };
+            }
+        }
+
+        // A file of a single line, or a first record too long to look at, is
+        // parsed as concatenated values, which reads NDJSON just as well
+        if read == 0 || head.len() >= DETECT_LIMIT {
+            break JsonFormat::Concatenated;
+        }
+    };
+    tracing::debug!("Detected {:?} JSON input", format);
+    Ok((format, Cursor::new(head).chain(reader)))
+}
+
+/// A record of a JSON file that could not be read as an event
+#[derive(Debug, Clone)]
+pub struct InvalidRecord {
+    /// Position of the record among the records of its file, from 0
+    pub index: usize,
+    /// Byte offset the record starts at in its file, or where reading
+    /// stopped for input that is not valid JSON
+    pub offset: u64,
+    /// The serde error message
+    pub message: String,
+    /// The record as it appeared in the file, on a single line, when it
+    /// could be read at all
+    pub raw: Option<String>,
+}
+
+/// Reader that adds up the bytes read through it, for the caller to tell
+/// where a deserializer reading from it is
+struct CountingReader<'a, R> {
+    inner: R,
+    count: &'a Cell<u64>,
+}
 
-                    // Write actor directly to file
-                    let actor_json = serde_json::to_string(actor_value)?;
-                    output_file.write_all(actor_json.as_bytes())?;
-                } else {
-                    tracing::debug!("No actor found in record at index {}", index);
+impl<R: Read> Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count.set(self.count.get() + read as u64);
+        Ok(read)
+    }
+}
+
+/// Turns the records of one file into events, counting them as it goes
+struct Records<'a> {
+    /// Value of the `mode` label on the parsing metrics
+    mode: &'static str,
+    /// Records seen so far, whether they are events or not
+    seen: usize,
+    parsed: usize,
+    on_event: &'a mut dyn FnMut(Event) -> Result<(), AppError>,
+    on_invalid: &'a mut dyn FnMut(InvalidRecord) -> Result<(), AppError>,
+}
+
+impl Records<'_> {
+    /// Parses one record found at `offset` and hands it on as an event, or
+    /// as an invalid record
+    fn record(&mut self, offset: u64, raw: &[u8]) -> Result<(), AppError> {
+        let index = self.seen;
+        self.seen += 1;
+        match serde_json::from_slice::<Event>(raw) {
+            Ok(event) => {
+                METRICS.records_parsed.with_label_values(&[self.mode]).inc();
+                self.parsed += 1;
+                (self.on_event)(event)
+            }
+            Err(e) => (self.on_invalid)(InvalidRecord {
+                index,
+                offset,
+                message: e.to_string(),
+                // Line breaks in valid JSON sit between tokens, so dropping
+                // them along with the indentation around them keeps the record
+                raw: Some(String::from_utf8_lossy(raw).lines().map(str::trim).collect()),
+            }),
+        }
+    }
+
+    /// Reports input that is not valid JSON at `offset`. Reading errors, and
+    /// the input going over a size limit, are returned as they are: nothing
+    /// after them can be read.
+    fn unreadable(&mut self, offset: u64, error: serde_json::Error) -> Result<(), AppError> {
+        if error.is_io() {
+            return Err(error.into());
+        }
+        let index = self.seen;
+        self.seen += 1;
+        (self.on_invalid)(InvalidRecord {
+            index,
+            offset,
+            message: error.to_string(),
+            raw: None,
+        })
+    }
+}
+
+/// Walks a top-level array one element at a time, handing each element to
+/// `on_element` as raw JSON so only one element is held in memory.
+///
+/// An error from `on_element` stops the walk and is left in `failure`, as
+/// the visitor itself can only return deserializer errors.
+struct ArrayElements<'a> {
+    on_element: &'a mut dyn FnMut(Box<RawValue>) -> Result<(), AppError>,
+    failure: &'a mut Option<AppError>,
+}
+
+impl<'de> Visitor<'de> for ArrayElements<'_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("an array of events")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(element) = seq.next_element::<Box<RawValue>>()? {
+            if let Err(e) = (self.on_element)(element) {
+                *self.failure = Some(e);
+                return Err(de::Error::custom("stopped after a processing error"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Streams a JSON file record by record, handing each event to `on_event`
+/// without holding more than one record in memory. The file may hold an
+/// array of events, NDJSON or concatenated events, see [`detect_format`];
+/// arrays are walked element by element rather than read as one value.
+///
+/// Records that are not events go to `on_invalid`, which decides whether
+/// parsing goes on by returning `Ok`. In NDJSON a line that is not valid
+/// JSON is a record like any other, as the next line starts a new one; in
+/// arrays and concatenated events nothing past a syntax error can be read,
+/// so it is reported once and the rest of the file is skipped.
+///
+/// `mode` labels the parsing metrics. Returns the number of events parsed.
+pub(crate) fn process_json_stream(
+    reader: impl Read,
+    mode: &'static str,
+    on_event: &mut dyn FnMut(Event) -> Result<(), AppError>,
+    on_invalid: &mut dyn FnMut(InvalidRecord) -> Result<(), AppError>,
+) -> Result<usize, AppError> {
+    let (format, reader) = detect_format(reader)?;
+    let mut records = Records {
+        mode,
+        seen: 0,
+        parsed: 0,
+        on_event,
+        on_invalid,
+    };
+
+    match format {
+        JsonFormat::Array => {
+            let consumed = Cell::new(0);
+            let mut failure = None;
+            let mut deserializer = serde_json::Deserializer::from_reader(CountingReader {
+                inner: reader,
+                count: &consumed,
+            });
+            let result = deserializer
+                .deserialize_seq(ArrayElements {
+                    on_element: &mut |element| {
+                        // serde_json reads byte by byte, so an element has
+                        // just been read up to its last byte
+                        let raw = element.get();
+                        let offset = consumed.get().saturating_sub(raw.len() as u64);
+                        records.record(offset, raw.as_bytes())
+                    },
+                    failure: &mut failure,
+                })
+                .and_then(|()| deserializer.end());
+
+            if let Some(e) = failure {
+                return Err(e);
+            }
+            if let Err(e) = result {
+                records.unreadable(consumed.get(), e)?;
+            }
+        }
+        JsonFormat::Ndjson => {
+            let mut reader = BufReader::new(reader);
+            let mut line = Vec::new();
+            let mut offset = 0;
+            loop {
+                line.clear();
+                let read = reader.read_until(b'\n', &mut line)?;
+                if read == 0 {
+                    break;
                 }
+                if !line.trim_ascii().is_empty() {
+                    records.record(offset, &line)?;
+                }
+                offset += read as u64;
             }
-            Err(e) => {
-                tracing::error!("Error parsing record at index {}: {}", index, e);
+        }
+        JsonFormat::Concatenated => {
+            // Read as raw JSON first so a record of the wrong shape is
+            // reported without ending the stream
+            let mut stream =
+                serde_json::Deserializer::from_reader(reader).into_iter::<Box<RawValue>>();
+            while let Some(record) = stream.next() {
+                match record {
+                    Ok(raw) => {
+                        let raw = raw.get();
+                        let offset = (stream.byte_offset() - raw.len()) as u64;
+                        records.record(offset, raw.as_bytes())?;
+                    }
+                    // The stream stops after a syntax error, whose offset is
+                    // where the value it was reading starts
+                    Err(e) => records.unreadable(stream.byte_offset() as u64, e)?,
+                }
             }
         }
     }
 
-    // Close JSON array
-    output_file.write_all(b"]")?;
-    output_file.flush()?;
+    Ok(records.parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(())
+    /// Parses `input`, returning the ids of its events and its invalid records
+    fn parse(input: &str) -> (Vec<Option<String>>, Vec<InvalidRecord>) {
+        let (mut events, mut invalid) = (Vec::new(), Vec::new());
+        let parsed = process_json_stream(
+            input.as_bytes(),
+            "test",
+            &mut |event| {
+                events.push(event.id);
+                Ok(())
+            },
+            &mut |record| {
+                invalid.push(record);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(parsed, events.len());
+        (events, invalid)
+    }
+
+    fn offset(input: &str, record: &str) -> u64 {
+        input.find(record).unwrap() as u64
+    }
+
+    #[test]
+    fn formats_are_told_apart() {
+        let detect = |input: &str| detect_format(input.as_bytes()).unwrap().0;
+        assert_eq!(detect("  [{\"public\":true}]"), JsonFormat::Array);
+        assert_eq!(detect("{\"public\":true}\n{\"public\":true}\n"), JsonFormat::Ndjson);
+        assert_eq!(detect("{\n\"public\":true}\n"), JsonFormat::Concatenated);
+        assert_eq!(detect("{\"public\":true}"), JsonFormat::Concatenated);
+
+        let (_, mut reader) = detect_format("{\"public\":true}\n".as_bytes()).unwrap();
+        let mut again = String::new();
+        reader.read_to_string(&mut again).unwrap();
+        assert_eq!(again, "{\"public\":true}\n");
+    }
+
+    #[test]
+    fn ndjson_lines_are_records_even_when_not_json() {
+        let input = concat!(
+            "{\"id\":\"a\",\"public\":true}\n\n",
+            "{\"id\":\"b\"}\nnot json\n",
+            "{\"id\":\"c\",\"public\":false}\n",
+        );
+        let (events, invalid) = parse(input);
+        assert_eq!(events, vec![Some("a".to_string()), Some("c".to_string())]);
+
+        let found: Vec<_> = invalid.iter().map(|record| (record.index, record.offset)).collect();
+        assert_eq!(found, vec![(1, offset(input, "{\"id\":\"b\"}")), (2, offset(input, "not json"))]);
+        assert_eq!(invalid[0].raw.as_deref(), Some("{\"id\":\"b\"}"));
+        assert!(invalid[0].message.contains("public"));
+        assert_eq!(invalid[1].raw.as_deref(), Some("not json"));
+    }
+
+    #[test]
+    fn array_elements_are_reported_at_their_offset() {
+        let input = concat!(
+            "[{\"id\":\"a\",\"public\":true},\n",
+            "  {\"id\":\"b\",\"actor\":5,\"public\":true},\n",
+            "  {\"public\":true}]",
+        );
+        let (events, invalid) = parse(input);
+        assert_eq!(events, vec![Some("a".to_string()), None]);
+        assert_eq!(invalid.len(), 1);
+        assert_eq!((invalid[0].index, invalid[0].offset), (1, offset(input, "{\"id\":\"b\"")));
+        assert_eq!(invalid[0].raw.as_deref(), Some("{\"id\":\"b\",\"actor\":5,\"public\":true}"));
+    }
+
+    #[test]
+    fn a_syntax_error_ends_an_array_or_concatenated_file() {
+        let (events, invalid) = parse("[{\"public\":true}, {\"public\" true}, {\"public\":true}]");
+        assert_eq!(events.len(), 1);
+        assert_eq!(invalid.len(), 1);
+        assert_eq!(invalid[0].index, 1);
+        assert!(invalid[0].raw.is_none());
+
+        let input = concat!(
+            "{\n  \"id\": \"a\",\n  \"public\": true\n}\n",
+            "{\n  \"id\": \"b\"\n} {\"public\" true} {\"public\":true}",
+        );
+        let (events, invalid) = parse(input);
+        assert_eq!(events, vec![Some("a".to_string())]);
+        let found: Vec<_> = invalid.iter().map(|record| (record.index, record.raw.as_deref())).collect();
+        assert_eq!(found, vec![(1, Some("{\"id\": \"b\"}")), (2, None)]);
+        assert_eq!(invalid[0].offset, offset(input, "{\n  \"id\": \"b\""));
+    }
+
+    #[test]
+    fn an_invalid_record_can_stop_parsing() {
+        let mut events = 0;
+        let result = process_json_stream(
+            "{\"public\":true}\n{}\n{\"public\":true}\n".as_bytes(),
+            "test",
+            &mut |_| {
+                events += 1;
+                Ok(())
+            },
+            &mut |record| Err(AppError::Processing(record.message)),
+        );
+        assert!(matches!(result, Err(AppError::Processing(_))));
+        assert_eq!(events, 1);
+    }
 }
@@ -0,0 +1,376 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::config::AppConfig;
+use crate::error::AppError;
+
+const MB: u64 = 1024 * 1024;
+
+/// A size limit an upload or archive went over
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LimitError {
+    /// The request body is bigger than `MAX_FILE_SIZE_MB`
+    UploadTooLarge { limit: u64 },
+    /// The archive has more entries than `MAX_ARCHIVE_ENTRIES`
+    TooManyEntries { limit: usize },
+    /// One entry decompresses to more than `MAX_ENTRY_SIZE_MB`
+    EntryTooLarge { name: String, limit: u64 },
+    /// All entries together decompress to more than `MAX_UNCOMPRESSED_SIZE_MB`
+    ArchiveTooLarge { limit: u64 },
+    /// One entry expands more than `MAX_COMPRESSION_RATIO` times
+    CompressionRatio { name: String, ratio: u64, limit: u64 },
+    /// An upload compressed as a whole expands more than
+    /// `MAX_COMPRESSION_RATIO` times
+    UploadCompressionRatio { ratio: u64, limit: u64 },
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UploadTooLarge { limit } => {
+                write!(f, "Upload exceeds the maximum size of {} MB", limit / MB)
+            }
+            Self::TooManyEntries { limit } => {
+                write!(f, "Archive contains more than {} entries", limit)
+            }
+            Self::EntryTooLarge { name, limit } => {
+                write!(f, "Entry {} exceeds the maximum uncompressed size of {} MB", name, limit / MB)
+            }
+            Self::ArchiveTooLarge { limit } => {
+                write!(f, "Archive exceeds the maximum uncompressed size of {} MB", limit / MB)
+            }
+            Self::CompressionRatio { name, ratio, limit } => write!(
+                f,
+                "Entry {} has a compression ratio of {}:1, above the limit of {}:1",
+                name, ratio, limit
+            ),
+            Self::UploadCompressionRatio { ratio, limit } => write!(
+                f,
+                "Upload has a compression ratio of {}:1, above the limit of {}:1",
+                ratio, limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+impl LimitError {
+    /// Recovers a limit violation raised through an I/O error by [`LimitedReader`]
+    pub fn from_io(error: &io::Error) -> Option<Self> {
+        error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<Self>())
+            .cloned()
+    }
+}
+
+/// Size limits applied to uploads and to the archives inside them
+#[derive(Debug, Clone, Copy)]
+pub struct ArchiveLimits {
+    pub max_upload_bytes: u64,
+    pub max_total_bytes: u64,
+    pub max_entry_bytes: u64,
+    pub max_entries: usize,
+    pub max_compression_ratio: u64,
+}
+
+impl ArchiveLimits {
+    pub fn from_config(config: &AppConfig) -> Self {
+        Self {
+            max_upload_bytes: config.max_file_size_mb as u64 * MB,
+            max_total_bytes: config.max_uncompressed_size_mb as u64 * MB,
+            max_entry_bytes: config.max_entry_size_mb as u64 * MB,
+            max_entries: config.max_archive_entries,
+            max_compression_ratio: config.max_compression_ratio,
+        }
+    }
+
+    /// Fails once `received` bytes of the upload exceed the limit
+    pub fn check_upload(&self, received: u64) -> Result<(), LimitError> {
+        if received > self.max_upload_bytes {
+            return Err(LimitError::UploadTooLarge {
+                limit: self.max_upload_bytes,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Running totals for one archive, checked against its [`ArchiveLimits`]
+/// before any entry is written or processed, and again as entries are read
+#[derive(Debug)]
+pub struct ArchiveBudget {
+    limits: ArchiveLimits,
+    entries: usize,
+    /// Shared with the readers of [`Self::limit_entry`], which add the bytes
+    /// entries produce past their declared size
+    total_bytes: Cell<u64>,
+    /// Bytes all entries have produced so far
+    produced_bytes: Cell<u64>,
+    /// Bytes read from the upload so far, when it is compressed as a whole,
+    /// see [`Self::count_compressed`]
+    compressed_bytes: Option<Rc<Cell<u64>>>,
+}
+
+impl ArchiveBudget {
+    pub fn new(limits: ArchiveLimits) -> Self {
+        Self {
+            limits,
+            entries: 0,
+            total_bytes: Cell::new(0),
+            produced_bytes: Cell::new(0),
+            compressed_bytes: None,
+        }
+    }
+
+    /// Counts the bytes read from an upload that is compressed as a whole,
+    /// such as a `.json.gz` file or a `.tar.gz` archive, whose entries carry
+    /// no compressed size of their own. What the entries produce is then held
+    /// to the compression ratio against the input read so far. The returned
+    /// reader goes under the decompressor.
+    pub fn count_compressed<R: Read>(&mut self, reader: R) -> CountedInput<R> {
+        let count = Rc::new(Cell::new(0));
+        self.compressed_bytes = Some(Rc::clone(&count));
+        CountedInput { inner: reader, count }
+    }
+
+    /// Adds bytes an entry produced, failing once they take the upload past
+    /// the compression ratio of its compressed input
+    fn check_expansion(&self, produced: u64) -> Result<(), LimitError> {
+        self.produced_bytes.set(self.produced_bytes.get() + produced);
+        let Some(compressed) = &self.compressed_bytes else {
+            return Ok(());
+        };
+        let limit = self.limits.max_compression_ratio;
+        match self.produced_bytes.get().checked_div(compressed.get()) {
+            Some(ratio) if ratio > limit => Err(LimitError::UploadCompressionRatio { ratio, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_total(&self) -> Result<(), LimitError> {
+        if self.total_bytes.get() > self.limits.max_total_bytes {
+            return Err(LimitError::ArchiveTooLarge {
+                limit: self.limits.max_total_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    /// Accounts for one entry using the sizes declared in its header
+    pub fn admit(&mut self, name: &str, size: u64, compressed_size: u64) -> Result<(), LimitError> {
+        self.entries += 1;
+        if self.entries > self.limits.max_entries {
+            return Err(LimitError::TooManyEntries {
+                limit: self.limits.max_entries,
+            });
+        }
+
+        if size > self.limits.max_entry_bytes {
+            return Err(LimitError::EntryTooLarge {
+                name: name.to_string(),
+                limit: self.limits.max_entry_bytes,
+            });
+        }
+
+        if let Some(ratio) = size.checked_div(compressed_size) {
+            if ratio > self.limits.max_compression_ratio {
+                return Err(LimitError::CompressionRatio {
+                    name: name.to_string(),
+                    ratio,
+                    limit: self.limits.max_compression_ratio,
+                });
+            }
+        }
+
+        self.total_bytes.set(self.total_bytes.get() + size);
+        self.check_total()
+    }
+
+    /// Copies an entry while enforcing the limits on the bytes actually
+    /// produced, in case the header under-reports the size or the entry is
+    /// decompressed once more, see [`Self::limit_entry`]
+    pub fn copy_entry(
+        &self,
+        name: &str,
+        declared_size: u64,
+        compressed_size: u64,
+        reader: &mut impl Read,
+        writer: &mut impl Write,
+    ) -> Result<u64, AppError> {
+        let mut reader = self.limit_entry(name, declared_size, compressed_size, reader);
+        Ok(io::copy(&mut reader, writer)?)
+    }
+
+    /// Wraps an entry reader so it fails as soon as it yields more than the
+    /// per-entry limit, its bytes past `declared_size` take the archive over
+    /// the total limit, or it has expanded more than the compression ratio
+    /// allows from the `compressed_size` bytes it is stored in. A
+    /// `compressed_size` of 0 means unknown and skips the ratio check.
+    pub fn limit_entry<R: Read>(
+        &self,
+        name: &str,
+        declared_size: u64,
+        compressed_size: u64,
+        reader: R,
+    ) -> LimitedReader<'_, R> {
+        LimitedReader {
+            inner: reader,
+            name: name.to_string(),
+            remaining: self.limits.max_entry_bytes,
+            declared: declared_size,
+            compressed: compressed_size,
+            produced: 0,
+            budget: self,
+        }
+    }
+}
+
+/// Reader that errors with a [`LimitError`] instead of returning more than
+/// its archive allows
+pub struct LimitedReader<'a, R> {
+    inner: R,
+    name: String,
+    /// Bytes left under the per-entry limit
+    remaining: u64,
+    /// Size the entry was admitted with
+    declared: u64,
+    /// Size the entry is stored in, before any decompression
+    compressed: u64,
+    produced: u64,
+    budget: &'a ArchiveBudget,
+}
+
+impl<R: Read> Read for LimitedReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Ask for one byte more than remains so an overrun is noticed
+        let max = buf.len().min(self.remaining.saturating_add(1) as usize);
+        let read = self.inner.read(&mut buf[..max])? as u64;
+        if read > self.remaining {
+            return Err(io::Error::other(LimitError::EntryTooLarge {
+                name: self.name.clone(),
+                limit: self.budget.limits.max_entry_bytes,
+            }));
+        }
+        self.remaining -= read;
+
+        // Only what goes past the declared size is not in the total yet
+        let unaccounted = (self.produced + read).saturating_sub(self.produced.max(self.declared));
+        self.produced += read;
+        if unaccounted > 0 {
+            let total = &self.budget.total_bytes;
+            total.set(total.get() + unaccounted);
+            self.budget.check_total().map_err(io::Error::other)?;
+        }
+
+        self.budget.check_expansion(read).map_err(io::Error::other)?;
+
+        let limit = self.budget.limits.max_compression_ratio;
+        if let Some(ratio) = self.produced.checked_div(self.compressed) {
+            if ratio > limit {
+                return Err(io::Error::other(LimitError::CompressionRatio {
+                    name: self.name.clone(),
+                    ratio,
+                    limit,
+                }));
+            }
+        }
+        Ok(read as usize)
+    }
+}
+
+/// Compressed input of an upload, counting the bytes read from it, see
+/// [`ArchiveBudget::count_compressed`]
+pub struct CountedInput<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountedInput<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count.set(self.count.get() + read as u64);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> ArchiveLimits {
+        ArchiveLimits {
+            max_upload_bytes: 1000,
+            max_total_bytes: 100,
+            max_entry_bytes: 60,
+            max_entries: 3,
+            max_compression_ratio: 10,
+        }
+    }
+
+    fn read_limited(budget: &ArchiveBudget, size: u64, compressed_size: u64, data: &[u8]) -> Result<u64, LimitError> {
+        let mut reader = budget.limit_entry("entry.json", size, compressed_size, data);
+        io::copy(&mut reader, &mut io::sink()).map_err(|e| LimitError::from_io(&e).unwrap())
+    }
+
+    #[test]
+    fn admit_checks_declared_sizes() {
+        let mut budget = ArchiveBudget::new(limits());
+        assert!(budget.admit("a", 50, 10).is_ok());
+        assert!(matches!(budget.admit("b", 61, 61), Err(LimitError::EntryTooLarge { .. })));
+        assert!(matches!(budget.admit("c", 55, 5), Err(LimitError::CompressionRatio { ratio: 11, .. })));
+
+        let mut budget = ArchiveBudget::new(limits());
+        budget.admit("a", 50, 50).unwrap();
+        budget.admit("b", 50, 50).unwrap();
+        budget.admit("c", 1, 1).unwrap_err();
+        assert!(matches!(budget.admit("d", 0, 0), Err(LimitError::TooManyEntries { limit: 3 })));
+    }
+
+    #[test]
+    fn entry_limit_applies_to_bytes_read() {
+        let budget = ArchiveBudget::new(limits());
+        assert_eq!(read_limited(&budget, 0, 0, &[b' '; 60]), Ok(60));
+        assert!(matches!(read_limited(&budget, 0, 0, &[b' '; 61]), Err(LimitError::EntryTooLarge { .. })));
+    }
+
+    #[test]
+    fn bytes_past_the_declared_size_count_towards_the_total() {
+        let mut budget = ArchiveBudget::new(limits());
+        budget.admit("a", 50, 50).unwrap();
+        // Within its declared size, the entry is already in the total
+        assert_eq!(read_limited(&budget, 50, 0, &[b' '; 50]), Ok(50));
+        assert_eq!(read_limited(&budget, 40, 0, &[b' '; 60]), Ok(60));
+        assert_eq!(budget.total_bytes.get(), 70);
+        assert!(matches!(read_limited(&budget, 0, 0, &[b' '; 31]), Err(LimitError::ArchiveTooLarge { limit: 100 })));
+    }
+
+    #[test]
+    fn ratio_applies_to_decompressed_bytes() {
+        let budget = ArchiveBudget::new(limits());
+        assert_eq!(read_limited(&budget, 5, 5, &[b' '; 54]), Ok(54));
+        assert!(matches!(
+            read_limited(&budget, 5, 5, &[b' '; 55]),
+            Err(LimitError::CompressionRatio { ratio: 11, limit: 10, .. })
+        ));
+        // Unknown compressed sizes are not checked
+        let budget = ArchiveBudget::new(limits());
+        assert!(read_limited(&budget, 0, 0, &[b' '; 55]).is_ok());
+    }
+
+    #[test]
+    fn uploads_compressed_as_a_whole_are_held_to_the_ratio() {
+        let mut budget = ArchiveBudget::new(limits());
+        let mut compressed = Vec::new();
+        budget.count_compressed(&[0u8; 5][..]).read_to_end(&mut compressed).unwrap();
+        assert_eq!(read_limited(&budget, 0, 0, &[b' '; 30]), Ok(30));
+        assert_eq!(read_limited(&budget, 0, 0, &[b' '; 24]), Ok(24));
+        assert!(matches!(
+            read_limited(&budget, 0, 0, &[b' '; 1]),
+            Err(LimitError::UploadCompressionRatio { ratio: 11, limit: 10 })
+        ));
+    }
+}
@@ -1,2 +1,14 @@
+pub(crate) mod actor_dedup;
+pub(crate) mod blocking_pool;
+pub(crate) mod compression;
+pub(crate) mod event_query;
+pub(crate) mod extraction;
+pub(crate) mod extractors;
 pub(crate) mod file_processing;
-pub(crate) mod json_processing;
\ No newline at end of file
+pub(crate) mod json_processing;
+pub(crate) mod limits;
+pub(crate) mod output;
+pub(crate) mod tabular;
+pub(crate) mod time_series;
+pub(crate) mod validation;
+pub(crate) mod zip_stream;
\ No newline at end of file
@@ -0,0 +1,251 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::SinkExt;
+use serde::Serialize;
+
+use crate::error::AppError;
+use crate::utils::event_query::Projection;
+use crate::utils::tabular::{Column, CsvWriter, ParquetWriter};
+
+/// Content type of newline-delimited JSON output
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Bytes of NDJSON gathered before they are sent as one response chunk
+const RESPONSE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Sending half of a streamed response body
+pub type ResponseSender = mpsc::Sender<Bytes>;
+
+/// File format extractor outputs are written in, selected with `format=`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    /// A single JSON array
+    #[default]
+    Json,
+    /// One JSON record per line
+    Ndjson,
+    /// A header row of column names, then one row per record
+    Csv,
+    /// Columnar Parquet file, compressed with Snappy
+    Parquet,
+}
+
+impl OutputFormat {
+    /// File extension outputs in this format are named with
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Ndjson => "ndjson",
+            Self::Csv => "csv",
+            Self::Parquet => "parquet",
+        }
+    }
+
+    /// Parses the `format` parameter, defaulting to a JSON array
+    pub fn parse(format: Option<&str>) -> Result<Self, AppError> {
+        match format.map(str::trim) {
+            None | Some("") => Ok(Self::default()),
+            Some(format) => format.parse(),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = AppError;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "json" => Ok(Self::Json),
+            "ndjson" => Ok(Self::Ndjson),
+            "csv" => Ok(Self::Csv),
+            "parquet" => Ok(Self::Parquet),
+            other => Err(AppError::InvalidRequest(format!(
+                "Unknown output format {}, expected one of json, ndjson, csv, parquet",
+                other
+            ))),
+        }
+    }
+}
+
+/// Where the records of one extractor go for a job.
+///
+/// A sink is opened once per job, before the first file is read, and every
+/// file's records are appended to it as they are produced. Nothing is
+/// buffered per file or rewritten when the next file starts, so the output
+/// of a job is the union of all of its files however many there are.
+pub enum OutputSink {
+    /// A single JSON array, written element by element
+    JsonArray(JsonArrayWriter),
+    /// One record per line
+    Ndjson(NdjsonWriter),
+    Csv(CsvWriter),
+    Parquet(ParquetWriter),
+    /// Another sink, handed only the fields kept by `fields=`
+    Projected(Projection, Box<OutputSink>),
+}
+
+impl OutputSink {
+    /// Creates the output file at `path`. CSV and Parquet lay records out
+    /// along `columns`; the JSON formats write records as they serialize.
+    pub fn create(path: &Path, format: OutputFormat, columns: Vec<Column>) -> Result<Self, AppError> {
+        Ok(match format {
+            OutputFormat::Json => Self::JsonArray(JsonArrayWriter::create(path)?),
+            OutputFormat::Ndjson => {
+                Self::Ndjson(NdjsonWriter::new(Box::new(BufWriter::new(File::create(path)?))))
+            }
+            OutputFormat::Csv => Self::Csv(CsvWriter::create(path, columns)?),
+            OutputFormat::Parquet => Self::Parquet(ParquetWriter::create(path, columns)?),
+        })
+    }
+
+    /// Cuts every record down to the fields of `projection`, if there is one
+    pub fn projected(self, projection: Option<&Projection>) -> Self {
+        match projection {
+            Some(projection) => Self::Projected(projection.clone(), Box::new(self)),
+            None => self,
+        }
+    }
+
+    /// Sink that streams records as NDJSON into a response body
+    pub fn response(sender: ResponseSender) -> Self {
+        let writer = BufWriter::with_capacity(RESPONSE_CHUNK_SIZE, ResponseWriter { sender });
+        Self::Ndjson(NdjsonWriter::new(Box::new(writer)))
+    }
+
+    /// Appends one record
+    pub fn push(&mut self, value: &impl Serialize) -> Result<(), AppError> {
+        match self {
+            Self::JsonArray(writer) => writer.push(value),
+            Self::Ndjson(writer) => writer.push(value),
+            Self::Csv(writer) => writer.push(value),
+            Self::Parquet(writer) => writer.push(value),
+            Self::Projected(projection, sink) => sink.push(&projection.apply(value)?),
+        }
+    }
+
+    /// Completes the output and returns the number of records written
+    pub fn close(self) -> Result<usize, AppError> {
+        match self {
+            Self::JsonArray(writer) => writer.close(),
+            Self::Ndjson(writer) => writer.close(),
+            Self::Csv(writer) => writer.close(),
+            Self::Parquet(writer) => writer.close(),
+            Self::Projected(_, sink) => sink.close(),
+        }
+    }
+}
+
+/// Writes values one at a time as the elements of a single JSON array
+pub struct JsonArrayWriter {
+    writer: BufWriter<File>,
+    items: usize,
+}
+
+impl JsonArrayWriter {
+    pub fn create(path: &Path) -> Result<Self, AppError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(b"[")?;
+        Ok(Self { writer, items: 0 })
+    }
+
+    pub fn push(&mut self, value: &impl Serialize) -> Result<(), AppError> {
+        if self.items > 0 {
+            self.writer.write_all(b",")?;
+        }
+        serde_json::to_writer(&mut self.writer, value)
+            .map_err(|e| AppError::Internal(format!("Failed to serialize output: {}", e)))?;
+        self.items += 1;
+        Ok(())
+    }
+
+    /// Closes the array and flushes the file
+    pub fn close(mut self) -> Result<usize, AppError> {
+        self.writer.write_all(b"]")?;
+        self.writer.flush()?;
+        Ok(self.items)
+    }
+}
+
+/// Writes values one at a time, each on its own line
+pub struct NdjsonWriter {
+    writer: Box<dyn Write + Send>,
+    items: usize,
+}
+
+impl NdjsonWriter {
+    pub fn new(writer: Box<dyn Write + Send>) -> Self {
+        Self { writer, items: 0 }
+    }
+
+    pub fn push(&mut self, value: &impl Serialize) -> Result<(), AppError> {
+        serde_json::to_writer(&mut self.writer, value)
+            .map_err(|e| AppError::Internal(format!("Failed to serialize output: {}", e)))?;
+        self.writer.write_all(b"\n")?;
+        self.items += 1;
+        Ok(())
+    }
+
+    pub fn close(mut self) -> Result<usize, AppError> {
+        self.writer.flush()?;
+        Ok(self.items)
+    }
+}
+
+/// Blocking [`Write`] into a streamed response body.
+///
+/// Each write becomes one body chunk and waits while the client is behind,
+/// so a slow reader holds back parsing instead of growing a buffer.
+struct ResponseWriter {
+    sender: ResponseSender,
+}
+
+impl Write for ResponseWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        futures::executor::block_on(self.sender.send(Bytes::copy_from_slice(buf))).map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "client closed the response stream")
+        })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn write(format: OutputFormat, values: &[Value]) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let mut sink = OutputSink::create(&path, format, Vec::new()).unwrap();
+        for value in values {
+            sink.push(value).unwrap();
+        }
+        assert_eq!(sink.close().unwrap(), values.len());
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn json_output_is_one_array() {
+        assert_eq!(write(OutputFormat::Json, &[]), "[]");
+        let values = [json!({"id": 1}), json!({"id": 2}), json!({"id": 3})];
+        let written: Value = serde_json::from_str(&write(OutputFormat::Json, &values)).unwrap();
+        assert_eq!(written, Value::Array(values.to_vec()));
+    }
+
+    #[test]
+    fn ndjson_output_has_one_record_per_line() {
+        assert_eq!(write(OutputFormat::Ndjson, &[]), "");
+        let values = [json!({"id": 1}), json!({"login": "a\nb"})];
+        assert_eq!(write(OutputFormat::Ndjson, &values), "{\"id\":1}\n{\"login\":\"a\\nb\"}\n");
+    }
+}
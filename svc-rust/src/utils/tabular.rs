@@ -0,0 +1,421 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::Arc;
+
+use parquet::basic::{Compression, LogicalType, Repetition, Type as PhysicalType};
+use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::types::Type;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error::AppError;
+
+/// Rows a Parquet writer holds before writing them out as a row group
+const ROW_GROUP_SIZE: usize = 64 * 1024;
+
+/// Type of a column in CSV and Parquet output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Int,
+    /// Unsigned integer, such as a repository id, which may not fit an
+    /// `Int`. Parquet stores it in an INT64 annotated as unsigned.
+    UInt,
+    Float,
+    String,
+    /// Nested value, such as a map of counts, written as JSON text
+    Json,
+}
+
+/// One column of tabular output, named after the field it is read from.
+/// Every column is nullable, as most fields of GH Archive records are
+/// optional.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Column {
+    pub name: &'static str,
+    pub kind: ColumnType,
+}
+
+impl Column {
+    pub const fn new(name: &'static str, kind: ColumnType) -> Self {
+        Self { name, kind }
+    }
+}
+
+/// Lays a record out along `columns`: each cell is the top-level field of the
+/// same name, or null when the record does not have it
+fn row(columns: &[Column], record: &impl Serialize) -> Result<Vec<Value>, AppError> {
+    let value = serde_json::to_value(record)
+        .map_err(|e| AppError::Internal(format!("Failed to serialize output: {}", e)))?;
+    let Value::Object(mut fields) = value else {
+        return Err(AppError::Internal(
+            "Tabular output needs records that serialize as objects".to_string(),
+        ));
+    };
+    Ok(columns
+        .iter()
+        .map(|column| fields.remove(column.name).unwrap_or(Value::Null))
+        .collect())
+}
+
+fn column_error(column: &Column, value: &Value) -> AppError {
+    AppError::Internal(format!(
+        "Column {} expects {:?} values, got {}",
+        column.name, column.kind, value
+    ))
+}
+
+/// Writes records as CSV rows under a header of the column names
+pub struct CsvWriter {
+    writer: csv::Writer<BufWriter<File>>,
+    columns: Vec<Column>,
+    items: usize,
+}
+
+impl CsvWriter {
+    pub fn create(path: &Path, columns: Vec<Column>) -> Result<Self, AppError> {
+        let mut writer = csv::Writer::from_writer(BufWriter::new(File::create(path)?));
+        writer
+            .write_record(columns.iter().map(|column| column.name))
+            .map_err(csv_error)?;
+        Ok(Self {
+            writer,
+            columns,
+            items: 0,
+        })
+    }
+
+    pub fn push(&mut self, record: &impl Serialize) -> Result<(), AppError> {
+        let cells = row(&self.columns, record)?.into_iter().map(|cell| match cell {
+            Value::Null => String::new(),
+            Value::String(text) => text,
+            other => other.to_string(),
+        });
+        self.writer.write_record(cells).map_err(csv_error)?;
+        self.items += 1;
+        Ok(())
+    }
+
+    pub fn close(mut self) -> Result<usize, AppError> {
+        self.writer.flush()?;
+        Ok(self.items)
+    }
+}
+
+fn csv_error(error: csv::Error) -> AppError {
+    match error.into_kind() {
+        csv::ErrorKind::Io(error) => error.into(),
+        other => AppError::Internal(format!("Failed to write CSV output: {:?}", other)),
+    }
+}
+
+/// Values of one column gathered for the current row group, with their
+/// definition levels: 0 for a null, 1 for a value. Unsigned values are kept
+/// with the same bits as signed ones, the way Parquet stores them.
+enum ColumnBuffer {
+    Int(Vec<i64>, Vec<i16>),
+    Float(Vec<f64>, Vec<i16>),
+    Bytes(Vec<ByteArray>, Vec<i16>),
+}
+
+impl ColumnBuffer {
+    fn new(kind: ColumnType) -> Self {
+        match kind {
+            ColumnType::Int | ColumnType::UInt => Self::Int(Vec::new(), Vec::new()),
+            ColumnType::Float => Self::Float(Vec::new(), Vec::new()),
+            ColumnType::String | ColumnType::Json => Self::Bytes(Vec::new(), Vec::new()),
+        }
+    }
+
+    fn levels(&mut self) -> &mut Vec<i16> {
+        match self {
+            Self::Int(_, levels) | Self::Float(_, levels) | Self::Bytes(_, levels) => levels,
+        }
+    }
+
+    /// Converts a value for the column, then adds it with its level. A value
+    /// of the wrong type is rejected without adding either.
+    fn push(&mut self, column: &Column, value: Value) -> Result<(), AppError> {
+        if value.is_null() {
+            self.levels().push(0);
+            return Ok(());
+        }
+
+        match self {
+            Self::Int(values, levels) => {
+                let int = match column.kind {
+                    ColumnType::UInt => value.as_u64().map(|int| int as i64),
+                    _ => value.as_i64(),
+                };
+                values.push(int.ok_or_else(|| column_error(column, &value))?);
+                levels.push(1);
+            }
+            Self::Float(values, levels) => {
+                values.push(value.as_f64().ok_or_else(|| column_error(column, &value))?);
+                levels.push(1);
+            }
+            Self::Bytes(values, levels) => {
+                let text = match value {
+                    Value::String(text) if column.kind == ColumnType::String => text,
+                    other => other.to_string(),
+                };
+                values.push(ByteArray::from(text.into_bytes()));
+                levels.push(1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes back the last value pushed, or the last null
+    fn pop(&mut self) {
+        if self.levels().pop() != Some(1) {
+            return;
+        }
+        match self {
+            Self::Int(values, _) => {
+                values.pop();
+            }
+            Self::Float(values, _) => {
+                values.pop();
+            }
+            Self::Bytes(values, _) => {
+                values.pop();
+            }
+        }
+    }
+}
+
+/// Writes records as a Parquet file, one optional column per schema column.
+/// Rows are buffered in memory up to a row group at a time.
+pub struct ParquetWriter {
+    writer: SerializedFileWriter<BufWriter<File>>,
+    columns: Vec<Column>,
+    buffers: Vec<ColumnBuffer>,
+    buffered: usize,
+    items: usize,
+}
+
+impl ParquetWriter {
+    pub fn create(path: &Path, columns: Vec<Column>) -> Result<Self, AppError> {
+        let fields = columns
+            .iter()
+            .map(|column| {
+                let (physical, logical) = match column.kind {
+                    ColumnType::Int => (PhysicalType::INT64, None),
+                    ColumnType::UInt => (
+                        PhysicalType::INT64,
+                        Some(LogicalType::Integer {
+                            bit_width: 64,
+                            is_signed: false,
+                        }),
+                    ),
+                    ColumnType::Float => (PhysicalType::DOUBLE, None),
+                    ColumnType::String => (PhysicalType::BYTE_ARRAY, Some(LogicalType::String)),
+                    ColumnType::Json => (PhysicalType::BYTE_ARRAY, Some(LogicalType::Json)),
+                };
+                Type::primitive_type_builder(column.name, physical)
+                    .with_repetition(Repetition::OPTIONAL)
+                    .with_logical_type(logical)
+                    .build()
+                    .map(Arc::new)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(parquet_error)?;
+        let schema = Type::group_type_builder("schema")
+            .with_fields(fields)
+            .build()
+            .map_err(parquet_error)?;
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+
+        let writer = SerializedFileWriter::new(
+            BufWriter::new(File::create(path)?),
+            Arc::new(schema),
+            Arc::new(properties),
+        )
+        .map_err(parquet_error)?;
+        let buffers = columns.iter().map(|column| ColumnBuffer::new(column.kind)).collect();
+        Ok(Self {
+            writer,
+            columns,
+            buffers,
+            buffered: 0,
+            items: 0,
+        })
+    }
+
+    pub fn push(&mut self, record: &impl Serialize) -> Result<(), AppError> {
+        let cells = row(&self.columns, record)?;
+        for (index, cell) in cells.into_iter().enumerate() {
+            if let Err(error) = self.buffers[index].push(&self.columns[index], cell) {
+                // The columns before it already hold this row
+                self.buffers[..index].iter_mut().for_each(ColumnBuffer::pop);
+                return Err(error);
+            }
+        }
+        self.buffered += 1;
+        self.items += 1;
+        if self.buffered >= ROW_GROUP_SIZE {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered rows out as one row group
+    fn write_row_group(&mut self) -> Result<(), AppError> {
+        let mut row_group = self.writer.next_row_group().map_err(parquet_error)?;
+        for (column, buffer) in self.columns.iter().zip(&mut self.buffers) {
+            let Some(mut writer) = row_group.next_column().map_err(parquet_error)? else {
+                return Err(AppError::Internal(format!("Parquet schema has no column {}", column.name)));
+            };
+            let written = match buffer {
+                ColumnBuffer::Int(values, levels) => {
+                    writer.typed::<Int64Type>().write_batch(values, Some(levels), None)
+                }
+                ColumnBuffer::Float(values, levels) => {
+                    writer.typed::<DoubleType>().write_batch(values, Some(levels), None)
+                }
+                ColumnBuffer::Bytes(values, levels) => {
+                    writer.typed::<ByteArrayType>().write_batch(values, Some(levels), None)
+                }
+            };
+            written.map_err(parquet_error)?;
+            writer.close().map_err(parquet_error)?;
+            *buffer = ColumnBuffer::new(column.kind);
+        }
+        row_group.close().map_err(parquet_error)?;
+        self.buffered = 0;
+        Ok(())
+    }
+
+    /// Writes the last row group and the file footer
+    pub fn close(mut self) -> Result<usize, AppError> {
+        if self.buffered > 0 {
+            self.write_row_group()?;
+        }
+        self.writer.close().map_err(parquet_error)?;
+        Ok(self.items)
+    }
+}
+
+fn parquet_error(error: parquet::errors::ParquetError) -> AppError {
+    match error {
+        parquet::errors::ParquetError::External(error) => match error.downcast::<std::io::Error>() {
+            Ok(error) => (*error).into(),
+            Err(error) => AppError::Internal(format!("Failed to write Parquet output: {}", error)),
+        },
+        other => AppError::Internal(format!("Failed to write Parquet output: {}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::basic::ConvertedType;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::Field;
+    use serde_json::json;
+
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new("id", ColumnType::UInt),
+            Column::new("count", ColumnType::Int),
+            Column::new("name", ColumnType::String),
+            Column::new("types", ColumnType::Json),
+        ]
+    }
+
+    fn records() -> Vec<Value> {
+        vec![
+            json!({"id": u64::MAX, "count": -3, "name": "a,b", "types": {"PushEvent": 2}}),
+            json!({"id": 7, "name": null, "extra": true}),
+        ]
+    }
+
+    #[test]
+    fn csv_rows_follow_the_columns() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.csv");
+        let mut writer = CsvWriter::create(&path, columns()).unwrap();
+        for record in records() {
+            writer.push(&record).unwrap();
+        }
+        assert_eq!(writer.close().unwrap(), 2);
+
+        let written = std::fs::read_to_string(path).unwrap();
+        assert_eq!(
+            written,
+            "id,count,name,types\n18446744073709551615,-3,\"a,b\",\"{\"\"PushEvent\"\":2}\"\n7,,,\n"
+        );
+    }
+
+    #[test]
+    fn parquet_keeps_unsigned_ids_and_nulls() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.parquet");
+        let mut writer = ParquetWriter::create(&path, columns()).unwrap();
+        for record in records() {
+            writer.push(&record).unwrap();
+        }
+        assert_eq!(writer.close().unwrap(), 2);
+
+        let reader = SerializedFileReader::new(File::open(path).unwrap()).unwrap();
+        let schema = reader.metadata().file_metadata().schema_descr_ptr();
+        assert_eq!(schema.column(0).converted_type(), ConvertedType::UINT_64);
+        let rows = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap().into_columns().into_iter().map(|(_, field)| field).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            vec![
+                vec![
+                    Field::ULong(u64::MAX),
+                    Field::Long(-3),
+                    Field::Str("a,b".to_string()),
+                    Field::Str(r#"{"PushEvent":2}"#.to_string()),
+                ],
+                vec![Field::ULong(7), Field::Null, Field::Null, Field::Null],
+            ]
+        );
+    }
+
+    #[test]
+    fn values_of_the_wrong_type_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = ParquetWriter::create(&dir.path().join("out.parquet"), columns()).unwrap();
+        assert!(matches!(writer.push(&json!({"id": -1})), Err(AppError::Internal(_))));
+        assert!(matches!(writer.push(&json!({"count": "many"})), Err(AppError::Internal(_))));
+        assert!(matches!(writer.push(&json!([1, 2])), Err(AppError::Internal(_))));
+    }
+
+    #[test]
+    fn rejected_rows_leave_no_values_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.parquet");
+        let mut writer = ParquetWriter::create(&path, columns()).unwrap();
+        assert!(writer.push(&json!({"id": 1, "count": 2, "name": "a", "types": 3})).is_ok());
+        assert!(writer.push(&json!({"id": 2, "count": "many", "name": "b"})).is_err());
+        assert!(writer.push(&json!({"id": 3, "count": -1.5})).is_err());
+        assert!(writer.push(&json!({"id": 4, "name": "d"})).is_ok());
+        assert_eq!(writer.close().unwrap(), 2);
+
+        let reader = SerializedFileReader::new(File::open(path).unwrap()).unwrap();
+        let rows = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap().into_columns().into_iter().map(|(_, field)| field).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            vec![
+                vec![Field::ULong(1), Field::Long(2), Field::Str("a".to_string()), Field::Str("3".to_string())],
+                vec![Field::ULong(4), Field::Null, Field::Str("d".to_string()), Field::Null],
+            ]
+        );
+    }
+}
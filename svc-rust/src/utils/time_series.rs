@@ -0,0 +1,296 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use chrono::{DateTime, Timelike, Utc};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::error::AppError;
+use crate::types::Event;
+use crate::utils::extractors::Extractor;
+use crate::utils::output::OutputSink;
+
+/// Width of the time buckets events are counted in, selected with `bucket=`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeBucket {
+    #[default]
+    Hour,
+    Day,
+}
+
+impl TimeBucket {
+    /// Parses the `bucket` parameter, defaulting to hourly buckets
+    pub fn parse(bucket: Option<&str>) -> Result<Self, AppError> {
+        match bucket.map(str::trim) {
+            None | Some("") => Ok(Self::default()),
+            Some(bucket) => bucket.parse(),
+        }
+    }
+
+    /// Start of the bucket `time` falls in
+    pub fn start(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let hour = match self {
+            Self::Hour => time.hour(),
+            Self::Day => 0,
+        };
+        time.date_naive()
+            .and_hms_opt(hour, 0, 0)
+            .map_or(time, |start| start.and_utc())
+    }
+}
+
+impl FromStr for TimeBucket {
+    type Err = AppError;
+
+    fn from_str(bucket: &str) -> Result<Self, Self::Err> {
+        match bucket {
+            "hour" => Ok(Self::Hour),
+            "day" => Ok(Self::Day),
+            other => Err(AppError::InvalidRequest(format!(
+                "Unknown bucket {}, expected hour or day",
+                other
+            ))),
+        }
+    }
+}
+
+/// Counts events per time bucket and `type`, written as
+/// `{"bucket", "type", "count"}` entries ordered by bucket once all events
+/// have been seen. Events without a `created_at` are not counted.
+pub struct TypeSeriesExtractor {
+    bucket: TimeBucket,
+    counts: BTreeMap<(DateTime<Utc>, String), u64>,
+}
+
+impl TypeSeriesExtractor {
+    pub fn new(bucket: TimeBucket) -> Self {
+        Self {
+            bucket,
+            counts: BTreeMap::new(),
+        }
+    }
+}
+
+impl Extractor for TypeSeriesExtractor {
+    fn name(&self) -> &'static str {
+        "type_series"
+    }
+
+    fn extract(&mut self, event: &Event, _out: &mut OutputSink) -> Result<(), AppError> {
+        let Some(created_at) = event.created_at else {
+            return Ok(());
+        };
+        let event_type = event.type_field.as_deref().unwrap_or("unknown");
+        *self
+            .counts
+            .entry((self.bucket.start(created_at), event_type.to_string()))
+            .or_default() += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut OutputSink) -> Result<(), AppError> {
+        for ((bucket, event_type), count) in &self.counts {
+            out.push(&json!({ "bucket": bucket, "type": event_type, "count": count }))?;
+        }
+        Ok(())
+    }
+}
+
+/// Identifies a repository by its id, or by its name when it has no id.
+/// Ids are ordered numerically, ahead of the names.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum RepoKey {
+    Id(u64),
+    Name(String),
+}
+
+/// Events one repository had in one bucket
+struct RepoCount {
+    id: Option<u64>,
+    name: Option<String>,
+    count: u64,
+}
+
+/// Counts events per time bucket and repository, written as
+/// `{"bucket", "repo_id", "repo_name", "count"}` entries ordered by bucket
+/// once all events have been seen. Events without a `created_at` or a repo
+/// are not counted.
+pub struct RepoSeriesExtractor {
+    bucket: TimeBucket,
+    counts: BTreeMap<(DateTime<Utc>, RepoKey), RepoCount>,
+}
+
+impl RepoSeriesExtractor {
+    pub fn new(bucket: TimeBucket) -> Self {
+        Self {
+            bucket,
+            counts: BTreeMap::new(),
+        }
+    }
+}
+
+impl Extractor for RepoSeriesExtractor {
+    fn name(&self) -> &'static str {
+        "repo_series"
+    }
+
+    fn extract(&mut self, event: &Event, _out: &mut OutputSink) -> Result<(), AppError> {
+        let (Some(created_at), Some(repo)) = (event.created_at, &event.repo) else {
+            return Ok(());
+        };
+        let key = match (&repo.id, &repo.name) {
+            (Some(id), _) => RepoKey::Id(*id),
+            (None, Some(name)) => RepoKey::Name(name.clone()),
+            (None, None) => return Ok(()),
+        };
+        let entry = self
+            .counts
+            .entry((self.bucket.start(created_at), key))
+            .or_insert_with(|| RepoCount {
+                id: repo.id,
+                name: repo.name.clone(),
+                count: 0,
+            });
+        entry.count += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut OutputSink) -> Result<(), AppError> {
+        for ((bucket, _), repo) in &self.counts {
+            out.push(&json!({
+                "bucket": bucket,
+                "repo_id": repo.id,
+                "repo_name": repo.name,
+                "count": repo.count,
+            }))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::output::OutputFormat;
+    use serde_json::Value;
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    /// Runs `extractor` over events of the given `type`, `repo.id` and
+    /// `created_at`, returning the records it writes
+    fn run(extractor: impl Extractor, events: &[(&str, u64, &str)]) -> Vec<Value> {
+        let events = events.iter().map(|(event_type, repo, created_at)| {
+            json!({
+                "type": event_type,
+                "public": true,
+                "repo": {"id": repo, "name": format!("r{}", repo)},
+                "created_at": created_at,
+            })
+        });
+        run_events(extractor, events)
+    }
+
+    /// Runs `extractor` over the given events, returning the records it writes
+    fn run_events(mut extractor: impl Extractor, events: impl IntoIterator<Item = Value>) -> Vec<Value> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let mut out = OutputSink::create(&path, OutputFormat::Ndjson, Vec::new()).unwrap();
+        for event in events {
+            let event: Event = serde_json::from_value(event).unwrap();
+            extractor.extract(&event, &mut out).unwrap();
+        }
+        extractor.finish(&mut out).unwrap();
+        out.close().unwrap();
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn buckets_start_on_the_hour_or_day_in_utc() {
+        let time = utc("2024-03-01T23:59:59.900+01:00");
+        assert_eq!(TimeBucket::Hour.start(time), utc("2024-03-01T22:00:00Z"));
+        assert_eq!(TimeBucket::Day.start(time), utc("2024-03-01T00:00:00Z"));
+        assert_eq!(TimeBucket::parse(None).unwrap(), TimeBucket::Hour);
+        assert_eq!(TimeBucket::parse(Some(" day ")).unwrap(), TimeBucket::Day);
+        assert!(matches!(TimeBucket::parse(Some("week")), Err(AppError::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn types_are_counted_per_bucket_across_offsets() {
+        let records = run(
+            TypeSeriesExtractor::new(TimeBucket::Hour),
+            &[
+                ("PushEvent", 1, "2024-03-01T10:15:00Z"),
+                ("PushEvent", 1, "2024-03-01T12:45:00+02:00"),
+                ("PushEvent", 1, "2024-03-01T10:59:59"),
+                ("WatchEvent", 1, "2024-03-01T09:00:00Z"),
+                ("PushEvent", 1, "not a time"),
+            ],
+        );
+        assert_eq!(
+            records,
+            vec![
+                json!({"bucket": "2024-03-01T09:00:00Z", "type": "WatchEvent", "count": 1}),
+                json!({"bucket": "2024-03-01T10:00:00Z", "type": "PushEvent", "count": 3}),
+            ]
+        );
+    }
+
+    #[test]
+    fn repositories_are_counted_per_day() {
+        let records = run(
+            RepoSeriesExtractor::new(TimeBucket::Day),
+            &[
+                ("PushEvent", u64::MAX, "2024-03-01T01:00:00+03:00"),
+                ("PushEvent", 2, "2024-03-01T10:00:00Z"),
+                ("IssuesEvent", 2, "2024-03-01T23:00:00Z"),
+            ],
+        );
+        assert_eq!(
+            records,
+            vec![
+                json!({
+                    "bucket": "2024-02-29T00:00:00Z",
+                    "repo_id": u64::MAX,
+                    "repo_name": format!("r{}", u64::MAX),
+                    "count": 1,
+                }),
+                json!({"bucket": "2024-03-01T00:00:00Z", "repo_id": 2, "repo_name": "r2", "count": 2}),
+            ]
+        );
+    }
+
+    #[test]
+    fn repositories_are_ordered_by_id_and_kept_apart_from_names() {
+        let event = |repo: Value| {
+            json!({"type": "PushEvent", "public": true, "repo": repo, "created_at": "2024-03-01T10:00:00Z"})
+        };
+        let records = run_events(
+            RepoSeriesExtractor::new(TimeBucket::Hour),
+            [
+                event(json!({"name": "123"})),
+                event(json!({"id": 10})),
+                event(json!({"id": 123, "name": "a/b"})),
+                event(json!({"id": 9})),
+                event(json!({"name": "123"})),
+                event(json!({})),
+            ],
+        );
+        let bucket = "2024-03-01T10:00:00Z";
+        assert_eq!(
+            records,
+            vec![
+                json!({"bucket": bucket, "repo_id": 9, "repo_name": null, "count": 1}),
+                json!({"bucket": bucket, "repo_id": 10, "repo_name": null, "count": 1}),
+                json!({"bucket": bucket, "repo_id": 123, "repo_name": "a/b", "count": 1}),
+                json!({"bucket": bucket, "repo_id": null, "repo_name": "123", "count": 2}),
+            ]
+        );
+    }
+}
@@ -0,0 +1,292 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::error::AppError;
+use crate::metrics::METRICS;
+use crate::utils::json_processing::InvalidRecord;
+
+/// File in the output dir listing every invalid record of a job, one JSON
+/// object per line
+pub const ERRORS_FILE: &str = "errors.ndjson";
+
+/// File in the output dir quarantined records are copied to, one per line
+pub const QUARANTINE_FILE: &str = "quarantine.ndjson";
+
+/// Invalid records listed in the response summary; the full list is only in
+/// [`ERRORS_FILE`]
+const REPORTED_ERRORS: usize = 100;
+
+/// What happens to a record that cannot be read as an event, selected with
+/// `validation=`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationPolicy {
+    /// The first invalid record fails the job
+    FailFast,
+    /// Invalid records are reported and left out
+    Skip,
+    /// Invalid records are reported, left out and copied as they are to
+    /// [`QUARANTINE_FILE`], to be fixed and uploaded again
+    Quarantine,
+}
+
+impl ValidationPolicy {
+    /// Parses the `validation` parameter; `None` leaves the choice to the
+    /// processing mode
+    pub fn parse(policy: Option<&str>) -> Result<Option<Self>, AppError> {
+        match policy.map(str::trim) {
+            None | Some("") => Ok(None),
+            Some(policy) => policy.parse().map(Some),
+        }
+    }
+}
+
+impl FromStr for ValidationPolicy {
+    type Err = AppError;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "fail_fast" => Ok(Self::FailFast),
+            "skip" => Ok(Self::Skip),
+            "quarantine" => Ok(Self::Quarantine),
+            other => Err(AppError::InvalidRequest(format!(
+                "Unknown validation {}, expected one of fail_fast, skip, quarantine",
+                other
+            ))),
+        }
+    }
+}
+
+/// One invalid record, as listed in the error report
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordError {
+    /// File the record was read from, relative to the archive root
+    pub file: String,
+    /// Position of the record among the records of its file, from 0
+    pub index: usize,
+    /// Byte offset of the record in its file
+    pub offset: u64,
+    pub message: String,
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid record {} of {} at byte {}: {}",
+            self.index, self.file, self.offset, self.message
+        )
+    }
+}
+
+/// Invalid records of a job, returned in the processing summary
+#[derive(Debug, Clone, Serialize)]
+pub struct ValidationReport {
+    pub policy: ValidationPolicy,
+    pub invalid_records: usize,
+    /// The first invalid records; all of them are listed in `errors_file`
+    pub errors: Vec<RecordError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quarantine_file: Option<String>,
+}
+
+/// Applies a job's validation policy to its invalid records and writes the
+/// error report. The report files are only created once there is something
+/// to put in them.
+pub struct ErrorLog {
+    output_dir: PathBuf,
+    errors: Option<BufWriter<File>>,
+    quarantine: Option<BufWriter<File>>,
+    report: ValidationReport,
+}
+
+impl ErrorLog {
+    pub fn new(output_dir: &Path, policy: ValidationPolicy) -> Self {
+        Self {
+            output_dir: output_dir.to_path_buf(),
+            errors: None,
+            quarantine: None,
+            report: ValidationReport {
+                policy,
+                invalid_records: 0,
+                errors: Vec::new(),
+                errors_file: None,
+                quarantine_file: None,
+            },
+        }
+    }
+
+    /// Opens a report file in the output dir, noting its name in the report
+    fn open(output_dir: &Path, name: &str, listed: &mut Option<String>) -> Result<BufWriter<File>, AppError> {
+        let file = File::create(output_dir.join(name))?;
+        *listed = Some(name.to_string());
+        Ok(BufWriter::new(file))
+    }
+
+    /// Reports an invalid record of `file`. Fails under
+    /// [`ValidationPolicy::FailFast`], once the record has been written to
+    /// the report.
+    pub fn record(&mut self, file: &str, record: InvalidRecord) -> Result<(), AppError> {
+        let error = RecordError {
+            file: file.to_string(),
+            index: record.index,
+            offset: record.offset,
+            message: record.message,
+        };
+        tracing::debug!("{}", error);
+
+        let errors = match &mut self.errors {
+            Some(errors) => errors,
+            None => self.errors.insert(Self::open(
+                &self.output_dir,
+                ERRORS_FILE,
+                &mut self.report.errors_file,
+            )?),
+        };
+        serde_json::to_writer(&mut *errors, &error)
+            .map_err(|e| AppError::Internal(format!("Failed to write error report: {}", e)))?;
+        errors.write_all(b"\n")?;
+        self.report.invalid_records += 1;
+
+        match self.report.policy {
+            ValidationPolicy::FailFast => {
+                errors.flush()?;
+                return Err(AppError::Processing(error.to_string()));
+            }
+            ValidationPolicy::Skip => {}
+            ValidationPolicy::Quarantine => {
+                if let Some(raw) = record.raw {
+                    let quarantine = match &mut self.quarantine {
+                        Some(quarantine) => quarantine,
+                        None => self.quarantine.insert(Self::open(
+                            &self.output_dir,
+                            QUARANTINE_FILE,
+                            &mut self.report.quarantine_file,
+                        )?),
+                    };
+                    quarantine.write_all(raw.as_bytes())?;
+                    quarantine.write_all(b"\n")?;
+                }
+            }
+        }
+        METRICS.parse_errors_skipped.inc();
+        if self.report.errors.len() < REPORTED_ERRORS {
+            self.report.errors.push(error);
+        }
+        Ok(())
+    }
+
+    /// Flushes the report files and returns the report
+    pub fn finish(mut self) -> Result<ValidationReport, AppError> {
+        for writer in [&mut self.errors, &mut self.quarantine].into_iter().flatten() {
+            writer.flush()?;
+        }
+        if self.report.invalid_records > 0 {
+            tracing::warn!(
+                "Left out {} invalid records, listed in {}",
+                self.report.invalid_records,
+                ERRORS_FILE
+            );
+        }
+        Ok(self.report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid(index: usize, raw: Option<&str>) -> InvalidRecord {
+        InvalidRecord {
+            index,
+            offset: index as u64 * 10,
+            message: format!("bad record {}", index),
+            raw: raw.map(str::to_string),
+        }
+    }
+
+    fn lines(path: &Path) -> Vec<String> {
+        std::fs::read_to_string(path).unwrap().lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn policies_are_parsed() {
+        assert_eq!(ValidationPolicy::parse(None).unwrap(), None);
+        assert_eq!(ValidationPolicy::parse(Some(" ")).unwrap(), None);
+        assert_eq!(ValidationPolicy::parse(Some("skip")).unwrap(), Some(ValidationPolicy::Skip));
+        assert_eq!(
+            ValidationPolicy::parse(Some(" quarantine ")).unwrap(),
+            Some(ValidationPolicy::Quarantine)
+        );
+        assert_eq!(ValidationPolicy::parse(Some("fail_fast")).unwrap(), Some(ValidationPolicy::FailFast));
+        assert!(matches!(ValidationPolicy::parse(Some("ignore")), Err(AppError::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn a_clean_job_writes_no_report_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let report = ErrorLog::new(dir.path(), ValidationPolicy::Quarantine).finish().unwrap();
+        assert_eq!(report.invalid_records, 0);
+        assert!(report.errors_file.is_none() && report.quarantine_file.is_none());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn fail_fast_reports_the_record_before_failing() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = ErrorLog::new(dir.path(), ValidationPolicy::FailFast);
+        let Err(AppError::Processing(message)) = log.record("a.json", invalid(3, Some("{}"))) else {
+            panic!("expected the first invalid record to fail the job");
+        };
+        assert_eq!(message, "Invalid record 3 of a.json at byte 30: bad record 3");
+
+        let written = lines(&dir.path().join(ERRORS_FILE));
+        assert_eq!(written.len(), 1);
+        let error: serde_json::Value = serde_json::from_str(&written[0]).unwrap();
+        assert_eq!(
+            error,
+            serde_json::json!({"file": "a.json", "index": 3, "offset": 30, "message": "bad record 3"})
+        );
+        assert!(!dir.path().join(QUARANTINE_FILE).exists());
+    }
+
+    #[test]
+    fn skip_lists_every_record_but_reports_the_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = ErrorLog::new(dir.path(), ValidationPolicy::Skip);
+        for index in 0..REPORTED_ERRORS + 5 {
+            log.record("a.json", invalid(index, Some("{}"))).unwrap();
+        }
+        let report = log.finish().unwrap();
+
+        assert_eq!(report.invalid_records, REPORTED_ERRORS + 5);
+        assert_eq!(report.errors.len(), REPORTED_ERRORS);
+        assert_eq!(report.errors_file.as_deref(), Some(ERRORS_FILE));
+        assert!(report.quarantine_file.is_none());
+        assert_eq!(lines(&dir.path().join(ERRORS_FILE)).len(), REPORTED_ERRORS + 5);
+        assert!(!dir.path().join(QUARANTINE_FILE).exists());
+    }
+
+    #[test]
+    fn quarantine_copies_the_records_that_could_be_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = ErrorLog::new(dir.path(), ValidationPolicy::Quarantine);
+        log.record("a.json", invalid(0, Some(r#"{"id":1}"#))).unwrap();
+        log.record("a.json", invalid(1, None)).unwrap();
+        log.record("b.json", invalid(0, Some(r#"{"id":2}"#))).unwrap();
+        let report = log.finish().unwrap();
+
+        assert_eq!(report.invalid_records, 3);
+        assert_eq!(report.quarantine_file.as_deref(), Some(QUARANTINE_FILE));
+        assert_eq!(lines(&dir.path().join(QUARANTINE_FILE)), vec![r#"{"id":1}"#, r#"{"id":2}"#]);
+        let files: Vec<_> = report.errors.iter().map(|error| error.file.as_str()).collect();
+        assert_eq!(files, vec!["a.json", "a.json", "b.json"]);
+    }
+}
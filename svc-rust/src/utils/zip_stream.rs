@@ -0,0 +1,384 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::path::Path;
+
+use actix_multipart::Multipart;
+use bytes::{Buf, Bytes};
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use zip::read::read_zipfile_from_stream;
+use zip::result::ZipError;
+use zip::read::ZipFile;
+use zip::ZipArchive;
+
+use crate::error::AppError;
+use crate::metrics::METRICS;
+use crate::utils::extraction::{self, ExtractionReport};
+use crate::utils::file_processing::EXTRACT_FIELD;
+use crate::utils::compression;
+use crate::utils::limits::{ArchiveBudget, ArchiveLimits};
+
+/// Number of multipart chunks buffered between the request and the reader
+pub const CHUNK_BUFFER: usize = 16;
+
+/// Message the zip crate reports for entries whose sizes are only known from a
+/// data descriptor written after the entry data
+const DATA_DESCRIPTOR_UNSUPPORTED: &str = "The file length is not available in the local header";
+
+pub type ChunkSender = mpsc::Sender<io::Result<Bytes>>;
+pub type ChunkReceiver = mpsc::Receiver<io::Result<Bytes>>;
+
+/// Forwards every multipart chunk to the [`ChunkReader`] on the other end.
+///
+/// Stops early, without an error, when the reader hangs up because it has
+/// either finished or failed; the reader side reports what happened. Fails
+/// once the upload grows past the configured maximum size, or if it carries an
+/// `extract` form field.
+pub async fn forward_multipart(
+    mut payload: Multipart,
+    mut chunks: ChunkSender,
+    limits: &ArchiveLimits,
+) -> Result<(), AppError> {
+    let mut received = 0;
+    while let Some(item) = payload.next().await {
+        let mut field = item?;
+        // Processing is already under way by the time form fields arrive
+        if field.name() == EXTRACT_FIELD {
+            return Err(AppError::InvalidRequest(
+                "The extract form field cannot be used with stream=true, use the extract query parameter".to_string(),
+            ));
+        }
+        while let Some(chunk) = field.next().await {
+            if let Ok(data) = &chunk {
+                received += data.len() as u64;
+                METRICS.upload_bytes.inc_by(data.len() as u64);
+                // Dropping the sender makes the reader see a truncated archive
+                limits.check_upload(received)?;
+            }
+            let message = chunk.map_err(|e| io::Error::other(e.to_string()));
+            let failed = message.is_err();
+            if chunks.send(message).await.is_err() {
+                return Ok(());
+            }
+            if failed {
+                return Err(AppError::Upload("Upload was interrupted".to_string()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Blocking [`Read`] over the chunks of a multipart upload.
+///
+/// Every byte handed out is also spooled to disk, so if the archive cannot be
+/// streamed it can still be reopened through its central directory.
+pub struct ChunkReader {
+    chunks: ChunkReceiver,
+    current: Bytes,
+    spool: BufWriter<File>,
+}
+
+impl ChunkReader {
+    pub fn new(chunks: ChunkReceiver, spool: File) -> Self {
+        Self {
+            chunks,
+            current: Bytes::new(),
+            spool: BufWriter::new(spool),
+        }
+    }
+
+    /// Reads the rest of the upload so the spooled archive is complete
+    pub fn drain(&mut self) -> io::Result<()> {
+        io::copy(self, &mut io::sink())?;
+        self.spool.flush()
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match futures::executor::block_on(self.chunks.next()) {
+                Some(Ok(chunk)) => {
+                    self.spool.write_all(&chunk)?;
+                    self.current = chunk;
+                }
+                Some(Err(e)) => return Err(e),
+                None => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.current.len());
+        buf[..len].copy_from_slice(&self.current[..len]);
+        self.current.advance(len);
+        Ok(len)
+    }
+}
+
+/// How far streaming through the local file headers got
+#[derive(Debug, Clone)]
+pub enum StreamOutcome {
+    /// Every entry was either handed to the callback or skipped
+    Completed(ExtractionReport),
+    /// An entry only records its size in a data descriptor, so it and the
+    /// entries after it have to be read through the central directory, see
+    /// [`resume_zip_entries`]
+    NeedsCentralDirectory {
+        /// Local headers read before the one that stopped the stream
+        entries_read: usize,
+        report: ExtractionReport,
+    },
+}
+
+/// Checks one entry and hands it to `on_entry` if it is a JSON file, with its
+/// data held to the limits of `budget`
+fn visit_entry<F>(
+    entry: &mut ZipFile<'_>,
+    budget: &ArchiveBudget,
+    report: &mut ExtractionReport,
+    on_entry: &mut F,
+) -> Result<(), AppError>
+where
+    F: FnMut(&str, &mut dyn Read) -> Result<(), AppError>,
+{
+    let name = entry.name().to_string();
+    if let Err(reason) = extraction::resolve_entry_path(Path::new(""), &name) {
+        report.skip(&name, reason);
+        return Ok(());
+    }
+    if entry.is_dir() {
+        return Ok(());
+    }
+    let (size, compressed_size) = (entry.size(), entry.compressed_size());
+    if size == 0 {
+        return Err(AppError::Extraction(format!("File {} is corrupted", name)));
+    }
+    let Some(json) = compression::json_entry(&name, entry) else {
+        return Ok(());
+    };
+    on_entry(&name, &mut budget.limit_entry(&name, size, compressed_size, json))?;
+    report.files_extracted += 1;
+    Ok(())
+}
+
+/// Walks the local file headers of a ZIP archive as it arrives and hands
+/// every `.json` file entry to `on_entry` along with its name, decompressing
+/// `.json.gz` entries on the way.
+///
+/// Each entry is checked against `budget` from its local header before it is
+/// read, and its data is capped at the per-entry limit. Entry names are
+/// validated like extracted paths; local headers carry no file attributes, so
+/// symlinks cannot be told apart here and are simply read as data.
+pub fn stream_zip_entries<R, F>(
+    reader: &mut R,
+    budget: &mut ArchiveBudget,
+    mut on_entry: F,
+) -> Result<StreamOutcome, AppError>
+where
+    R: Read,
+    F: FnMut(&str, &mut dyn Read) -> Result<(), AppError>,
+{
+    let mut entries = 0;
+    let mut report = ExtractionReport::default();
+
+    loop {
+        let mut entry = match read_zipfile_from_stream(reader) {
+            Ok(Some(entry)) => entry,
+            // Reached the central directory
+            Ok(None) => break,
+            Err(ZipError::UnsupportedArchive(message)) if message == DATA_DESCRIPTOR_UNSUPPORTED => {
+                return Ok(StreamOutcome::NeedsCentralDirectory {
+                    entries_read: entries,
+                    report,
+                });
+            }
+            // Not even the first header is valid: this is not a ZIP archive
+            Err(ZipError::InvalidArchive(message)) if entries == 0 => {
+                return Err(AppError::UnsupportedType(message.to_string()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        entries += 1;
+        budget.admit(entry.name(), entry.size(), entry.compressed_size())?;
+        visit_entry(&mut entry, budget, &mut report, &mut on_entry)?;
+        // Dropping the entry skips whatever the callback left unread
+    }
+
+    if entries == 0 {
+        return Err(AppError::Extraction("ZIP archive is empty".to_string()));
+    }
+
+    Ok(StreamOutcome::Completed(report))
+}
+
+/// Carries on where [`stream_zip_entries`] stopped, reading the entries from
+/// the `entries_read`th local header on through the central directory of the
+/// whole archive. The entries streamed already are not handed out again.
+///
+/// Entries are taken in the order of their local headers, which is the
+/// order they were streamed in, whatever the order of the central
+/// directory. Symbolic links are skipped, as they can be told apart here.
+pub fn resume_zip_entries<R, F>(
+    reader: R,
+    budget: &mut ArchiveBudget,
+    report: &mut ExtractionReport,
+    entries_read: usize,
+    mut on_entry: F,
+) -> Result<(), AppError>
+where
+    R: Read + Seek,
+    F: FnMut(&str, &mut dyn Read) -> Result<(), AppError>,
+{
+    let mut archive = ZipArchive::new(reader)?;
+    let mut by_position = Vec::with_capacity(archive.len());
+    for index in 0..archive.len() {
+        by_position.push((archive.by_index_raw(index)?.header_start(), index));
+    }
+    by_position.sort_unstable();
+
+    for (_, index) in by_position.into_iter().skip(entries_read) {
+        let mut entry = archive.by_index(index)?;
+        budget.admit(entry.name(), entry.size(), entry.compressed_size())?;
+
+        if entry.is_symlink() {
+            report.skip(entry.name(), "symbolic links are not supported");
+            continue;
+        }
+        visit_entry(&mut entry, budget, report, &mut on_entry)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use zip::write::SimpleFileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+
+    fn limits(max_entries: usize) -> ArchiveLimits {
+        ArchiveLimits {
+            max_upload_bytes: u64::MAX,
+            max_total_bytes: 1 << 20,
+            max_entry_bytes: 1 << 20,
+            max_entries,
+            max_compression_ratio: 100,
+        }
+    }
+
+    /// Builds an archive of `entries`, flagging the `descriptor`th one as
+    /// having its sizes in a data descriptor, the way streaming writers do
+    fn archive(entries: &[(&str, CompressionMethod)], descriptor: Option<usize>) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, method) in entries {
+            writer
+                .start_file(*name, SimpleFileOptions::default().compression_method(*method))
+                .unwrap();
+            writer.write_all(format!("{{\"name\":\"{}\"}}", name).as_bytes()).unwrap();
+        }
+        let mut bytes = writer.finish().unwrap().into_inner();
+
+        if let Some(index) = descriptor {
+            let mut archive = ZipArchive::new(Cursor::new(bytes.clone())).unwrap();
+            let entry = archive.by_index_raw(index).unwrap();
+            // General purpose flags sit 6 bytes into a local header and 8
+            // bytes into a central directory header
+            for flags in [entry.header_start() as usize + 6, entry.central_header_start() as usize + 8] {
+                bytes[flags] |= 1 << 3;
+            }
+        }
+        bytes
+    }
+
+    /// Entries handed out with their content, whether streaming had to
+    /// resume, and the report
+    type Streamed = (Vec<(String, String)>, bool, ExtractionReport);
+
+    /// Streams `bytes`, resuming through the central directory if needed
+    fn stream(bytes: &[u8], max_entries: usize) -> Result<Streamed, AppError> {
+        let mut budget = ArchiveBudget::new(limits(max_entries));
+        let mut read = Vec::new();
+        let mut on_entry = |name: &str, reader: &mut dyn Read| {
+            let mut content = String::new();
+            reader.read_to_string(&mut content)?;
+            read.push((name.to_string(), content));
+            Ok(())
+        };
+        let outcome = stream_zip_entries(&mut Cursor::new(bytes), &mut budget, &mut on_entry)?;
+        let (resumed, report) = match outcome {
+            StreamOutcome::Completed(report) => (false, report),
+            StreamOutcome::NeedsCentralDirectory {
+                entries_read,
+                mut report,
+            } => {
+                resume_zip_entries(Cursor::new(bytes), &mut budget, &mut report, entries_read, &mut on_entry)?;
+                (true, report)
+            }
+        };
+        Ok((read, resumed, report))
+    }
+
+    fn names(read: &[(String, String)]) -> Vec<&str> {
+        read.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    #[test]
+    fn stored_and_deflated_entries_are_streamed_in_order() {
+        let bytes = archive(
+            &[
+                ("b.json", CompressionMethod::Stored),
+                ("dir/", CompressionMethod::Stored),
+                ("notes.txt", CompressionMethod::Stored),
+                ("dir/a.json", CompressionMethod::Deflated),
+            ],
+            None,
+        );
+        let (read, resumed, report) = stream(&bytes, 4).unwrap();
+        assert!(!resumed);
+        assert_eq!(names(&read), vec!["b.json", "dir/a.json"]);
+        assert_eq!(read[1].1, r#"{"name":"dir/a.json"}"#);
+        assert_eq!(report.files_extracted, 2);
+    }
+
+    #[test]
+    fn a_data_descriptor_entry_resumes_without_repeating_entries() {
+        let bytes = archive(
+            &[
+                ("a.json", CompressionMethod::Stored),
+                ("b.json", CompressionMethod::Deflated),
+                ("c.json", CompressionMethod::Deflated),
+                ("d.json", CompressionMethod::Stored),
+            ],
+            Some(2),
+        );
+        // Exactly as many entries as the archive holds, so none can be
+        // admitted twice
+        let (read, resumed, report) = stream(&bytes, 4).unwrap();
+        assert!(resumed);
+        assert_eq!(names(&read), vec!["a.json", "b.json", "c.json", "d.json"]);
+        assert_eq!(read[2].1, r#"{"name":"c.json"}"#);
+        assert_eq!(report.files_extracted, 4);
+
+        assert!(matches!(stream(&bytes, 3), Err(AppError::Limit(_))));
+    }
+
+    #[test]
+    fn data_descriptor_entries_are_reported_with_the_expected_message() {
+        // Matched against the zip crate's own message, so an upgrade that
+        // changes it has to fail here rather than in production
+        let bytes = archive(&[("a.json", CompressionMethod::Deflated)], Some(0));
+        match read_zipfile_from_stream(&mut Cursor::new(&bytes)) {
+            Err(ZipError::UnsupportedArchive(message)) => assert_eq!(message, DATA_DESCRIPTOR_UNSUPPORTED),
+            Err(e) => panic!("expected the data descriptor error, got {}", e),
+            Ok(_) => panic!("expected the data descriptor error, got an entry"),
+        }
+        let (read, resumed, _) = stream(&bytes, 1).unwrap();
+        assert!(resumed);
+        assert_eq!(names(&read), vec!["a.json"]);
+    }
+
+    #[test]
+    fn input_that_is_not_a_zip_archive_is_unsupported() {
+        assert!(matches!(stream(b"not a zip archive at all, just text", 1), Err(AppError::UnsupportedType(_))));
+        assert!(matches!(stream(&[], 1), Err(AppError::Extraction(_)) | Err(AppError::Internal(_))));
+    }
+}
@@ -0,0 +1,165 @@
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+/// Isolated directory tree for a single upload.
+///
+/// Every upload gets its own job ID, and the uploaded archive, the extracted
+/// JSON files and the produced output all live under directories named after
+/// that ID, so concurrent uploads never share files.
+#[derive(Debug, Clone)]
+pub struct Workspace {
+    pub job_id: Uuid,
+    /// Path the uploaded archive is written to
+    pub upload_path: PathBuf,
+    /// Directory the archive is extracted into
+    pub extract_dir: PathBuf,
+    /// Directory the processing results are written to
+    pub output_dir: PathBuf,
+    /// Directory for intermediate files, such as spilled extractor state
+    pub scratch_dir: PathBuf,
+}
+
+impl Workspace {
+    /// Allocates a new job ID and creates its directories.
+    ///
+    /// Layout:
+    /// - `{upload_dir}/{job_id}/{upload_file_name}`
+    /// - `{json_dir}/{job_id}/extracted/`
+    /// - `{json_dir}/{job_id}/output/`
+    /// - `{json_dir}/{job_id}/scratch/`, created on first use
+    pub fn create(
+        upload_dir: &str,
+        json_dir: &str,
+        upload_file_name: &str,
+    ) -> Result<Self, std::io::Error> {
+        let job_id = Uuid::new_v4();
+        let upload_root = Path::new(upload_dir).join(job_id.to_string());
+        let job_root = Path::new(json_dir).join(job_id.to_string());
+
+        let workspace = Self {
+            job_id,
+            upload_path: upload_root.join(upload_file_name),
+            extract_dir: job_root.join("extracted"),
+            output_dir: Self::output_dir(json_dir, &job_id),
+            scratch_dir: job_root.join("scratch"),
+        };
+
+        std::fs::create_dir_all(&upload_root)?;
+        std::fs::create_dir_all(&workspace.extract_dir)?;
+        std::fs::create_dir_all(&workspace.output_dir)?;
+
+        Ok(workspace)
+    }
+
+    /// Deletes the job's directories along with everything in them
+    pub fn remove(&self) -> Result<(), std::io::Error> {
+        remove_dirs([self.upload_path.parent(), self.output_dir.parent()].into_iter().flatten())
+    }
+
+    /// Deletes the uploaded archive, the extracted files and the scratch
+    /// dir once processing is over, keeping only the output
+    pub fn remove_inputs(&self) -> Result<(), std::io::Error> {
+        let upload_root = self.upload_path.parent().unwrap_or(&self.upload_path);
+        remove_dirs([upload_root, &self.extract_dir, &self.scratch_dir])
+    }
+
+    /// Directory the results of job `job_id` are written to, under the
+    /// `json_dir` the job was created with
+    pub fn output_dir(json_dir: &str, job_id: &Uuid) -> PathBuf {
+        Path::new(json_dir).join(job_id.to_string()).join("output")
+    }
+}
+
+/// Deletes each directory tree, ignoring the ones that are already gone
+fn remove_dirs<'a>(dirs: impl IntoIterator<Item = &'a Path>) -> Result<(), std::io::Error> {
+    for dir in dirs {
+        match std::fs::remove_dir_all(dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Deletes workspaces on a blocking thread, so request handlers and the job
+/// store never wait on the file system
+pub fn remove_in_background(workspaces: Vec<Workspace>) {
+    actix_web::rt::task::spawn_blocking(move || {
+        for workspace in workspaces {
+            if let Err(e) = workspace.remove() {
+                tracing::warn!("Failed to remove workspace of job {}: {}", workspace.job_id, e);
+            }
+        }
+    });
+}
+
+/// Workspace of an upload that has not been handed on yet. It is deleted
+/// when the guard is dropped, so an upload that fails or is abandoned
+/// halfway leaves nothing behind, unless it was [`kept`](Self::keep).
+#[derive(Debug)]
+pub struct WorkspaceGuard {
+    workspace: Workspace,
+    kept: bool,
+}
+
+impl WorkspaceGuard {
+    pub fn new(workspace: Workspace) -> Self {
+        Self {
+            workspace,
+            kept: false,
+        }
+    }
+
+    /// Hands the workspace on to whatever cleans it up from now on, such as
+    /// the job store
+    pub fn keep(mut self) -> Workspace {
+        self.kept = true;
+        self.workspace.clone()
+    }
+}
+
+impl Deref for WorkspaceGuard {
+    type Target = Workspace;
+
+    fn deref(&self) -> &Workspace {
+        &self.workspace
+    }
+}
+
+impl Drop for WorkspaceGuard {
+    fn drop(&mut self) {
+        if !self.kept {
+            tracing::debug!("Removing workspace of unfinished job {}", self.workspace.job_id);
+            remove_in_background(vec![self.workspace.clone()]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removing_inputs_keeps_the_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_string_lossy();
+        let (uploads, json) = (format!("{}/uploads", root), format!("{}/json", root));
+        let workspace = Workspace::create(&uploads, &json, "upload.zip").unwrap();
+        std::fs::write(&workspace.upload_path, b"zip").unwrap();
+        std::fs::write(workspace.extract_dir.join("a.json"), b"{}").unwrap();
+        std::fs::create_dir_all(&workspace.scratch_dir).unwrap();
+        std::fs::write(workspace.output_dir.join("actors.json"), b"[]").unwrap();
+
+        workspace.remove_inputs().unwrap();
+        assert!(!workspace.upload_path.parent().unwrap().exists());
+        assert!(!workspace.extract_dir.exists() && !workspace.scratch_dir.exists());
+        assert!(workspace.output_dir.join("actors.json").is_file());
+        // Already removed inputs are not an error
+        workspace.remove_inputs().unwrap();
+
+        workspace.remove().unwrap();
+        assert!(!workspace.output_dir.parent().unwrap().exists());
+    }
+}